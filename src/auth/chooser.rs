@@ -0,0 +1,76 @@
+use url::form_urlencoded;
+
+use crate::provider::Provider;
+
+/// The sign-in page listing every configured provider.
+///
+/// Each entry links back to the login route with the provider picked, so
+/// the return target and "remember me" survive the extra hop.
+pub fn render(providers: &[Provider], return_to: Option<&str>, remember: bool) -> String {
+    let entries: String = providers
+        .iter()
+        .map(|provider| {
+            let mut query = form_urlencoded::Serializer::new(String::new());
+            query.append_pair("provider", provider.name());
+            if let Some(return_to) = return_to {
+                query.append_pair("return_to", return_to);
+            }
+            if remember {
+                query.append_pair("remember", "true");
+            }
+            format!(
+                "      <li><a href=\"/?{}\">{}</a></li>\n",
+                escape_html(&query.finish()),
+                escape_html(&provider.config.label)
+            )
+        })
+        .collect();
+
+    format!(
+        "<!DOCTYPE html>\n\
+         <html lang=\"en\">\n\
+         <head>\n\
+         \x20 <meta charset=\"utf-8\">\n\
+         \x20 <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+         \x20 <title>Sign in</title>\n\
+         </head>\n\
+         <body>\n\
+         \x20 <main>\n\
+         \x20   <h1>Sign in</h1>\n\
+         \x20   <ul>\n\
+         {}\
+         \x20   </ul>\n\
+         \x20 </main>\n\
+         </body>\n\
+         </html>\n",
+        entries
+    )
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_html() {
+        assert_eq!(
+            escape_html(r#"<a href="x">Tom & 'Jerry'</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; &#39;Jerry&#39;&lt;/a&gt;"
+        );
+    }
+}
@@ -0,0 +1,165 @@
+use std::net::SocketAddr;
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Query, State},
+    http::{HeaderMap, HeaderValue, Method, Request, StatusCode, Uri},
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+
+use crate::{assertion::ASSERTION_HEADER, error::AppError, state::AppState};
+
+#[derive(Debug, Deserialize)]
+pub struct VerifyParams {
+    /// Answer browsers with a 401 rather than a redirect to login, for
+    /// nginx's `auth_request` which only understands 2xx, 401 and 403
+    redirect: Option<bool>,
+}
+
+/// Forward authentication for a reverse proxy in front of the website.
+///
+/// The proxy asks before passing a request on, sending the original method
+/// and URI in `X-Original-Method`/`X-Original-URI` (nginx) or
+/// `X-Forwarded-Method`/`X-Forwarded-Uri` (Traefik, Caddy) along with the
+/// client's headers. Allowed requests get a 200 carrying the identity
+/// headers to pass upstream, and any renewed session cookie.
+pub async fn verify(
+    State(state): State<AppState>,
+    Query(params): Query<VerifyParams>,
+    req: Request<Body>,
+) -> Result<Response, AppError> {
+    let original = original_request(&req)?;
+
+    let session = match crate::auth::authorize(&state, &original).await {
+        Ok(session) => session,
+        Err(AppError::LoginRequired { message, client_ip, path, .. }) if params.redirect == Some(false) => {
+            return Err(AppError::Unauthorized { message, client_ip, path });
+        }
+        Err(e) => return Err(e),
+    };
+
+    let mut headers = HeaderMap::new();
+    if let Some(session) = &session {
+        headers.extend(state.config.identity_headers.for_claims(&session.claims));
+        if let Some(signer) = &state.assertions {
+            let assertion = signer.sign(&session.claims, state.clock.now()).map_err(AppError::Internal)?;
+            headers.insert(ASSERTION_HEADER, HeaderValue::from_str(&assertion).map_err(|e| AppError::Internal(e.to_string()))?);
+        }
+        for cookie in &session.cookies {
+            if let Ok(val) = HeaderValue::from_str(&cookie.to_string()) {
+                headers.append("set-cookie", val);
+            }
+        }
+    }
+    Ok((StatusCode::OK, headers).into_response())
+}
+
+/// The request the reverse proxy is asking about.
+fn original_request(req: &Request<Body>) -> Result<Request<()>, AppError> {
+    let header = |names: [&str; 2]| names.iter().find_map(|name| req.headers().get(*name)?.to_str().ok());
+
+    let method = match header(["x-original-method", "x-forwarded-method"]) {
+        Some(method) => Method::from_bytes(method.as_bytes())
+            .map_err(|_| AppError::Auth("Invalid original request method".into()))?,
+        None => req.method().clone(),
+    };
+    let uri: Uri = header(["x-original-uri", "x-forwarded-uri"])
+        .ok_or_else(|| AppError::Auth("Missing X-Original-URI or X-Forwarded-Uri header".into()))?
+        .parse()
+        .map_err(|_| AppError::Auth("Invalid original request URI".into()))?;
+
+    let mut original = Request::new(());
+    *original.method_mut() = method;
+    *original.uri_mut() = uri;
+    *original.headers_mut() = req.headers().clone();
+    if let Some(connect_info) = req.extensions().get::<ConnectInfo<SocketAddr>>() {
+        original.extensions_mut().insert(*connect_info);
+    }
+    Ok(original)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    async fn create_test_state() -> AppState {
+        let state = AppState::for_tests(Config {
+            access_policy: toml::from_str(r#"
+                [[rules]]
+                effect = "deny"
+                paths = ["/admin/**"]
+                reason = "Admins only"
+            "#).unwrap(),
+            ..Config::for_tests()
+        })
+        .await;
+        let mut record = crate::store::tests::create_test_record(9999999999);
+        record.claims.email = Some("jdoe@example.com".to_string());
+        record.claims.groups = vec!["staff".to_string(), "editors".to_string()];
+        state.sessions.store().unwrap().save("test-session-id", &record).await.unwrap();
+        state
+    }
+
+    fn verify_request(headers: &[(&str, &str)]) -> Request<Body> {
+        let mut builder = Request::builder().uri("/auth/verify");
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    async fn send(state: &AppState, redirect: Option<bool>, headers: &[(&str, &str)]) -> Response {
+        verify(State(state.clone()), Query(VerifyParams { redirect }), verify_request(headers))
+            .await
+            .unwrap_or_else(IntoResponse::into_response)
+    }
+
+    const SESSION: (&str, &str) = ("cookie", "authy_session=test-session-id");
+    const BROWSER: (&str, &str) = ("accept", "text/html");
+
+    #[tokio::test]
+    async fn test_verify_signed_in() {
+        let state = create_test_state().await;
+
+        let response = send(&state, None, &[SESSION, ("x-original-uri", "/reports?year=2024")]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-authy-user"], "user-1");
+        assert_eq!(response.headers()["x-authy-email"], "jdoe@example.com");
+        assert_eq!(response.headers()["x-authy-groups"], "staff,editors");
+
+        // Traefik and Caddy headers, with the policy applied to the original request
+        let response = send(&state, None, &[SESSION, ("x-forwarded-method", "POST"), ("x-forwarded-uri", "/admin/users")]).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_verify_signed_out() {
+        let state = create_test_state().await;
+
+        // Browsers are sent to login and back to the original URI...
+        let response = send(&state, None, &[BROWSER, ("x-forwarded-uri", "/reports?year=2024")]).await;
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert_eq!(response.headers()["location"], "http://localhost:3000/?return_to=%2Freports%3Fyear%3D2024");
+
+        // ...unless the proxy cannot follow redirects, like API clients
+        let response = send(&state, Some(false), &[BROWSER, ("x-original-uri", "/reports")]).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = send(&state, None, &[("x-original-uri", "/reports")]).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = send(&state, None, &[SESSION]).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_verify_public_route() {
+        let mut state = create_test_state().await;
+        state.config.routes.add(crate::policy::routes::RouteClass::Public, "/static/**").unwrap();
+
+        let response = send(&state, None, &[SESSION, ("x-original-uri", "/static/app.js")]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get("x-authy-user").is_none());
+    }
+}
@@ -1,16 +1,46 @@
-use crate::{config::Config, error::AppError};
+pub mod chooser;
+pub mod forward;
+pub mod pkce;
+pub mod refresh;
+pub mod transaction;
+
+use crate::{
+    config::{Config, DEFAULT_PROVIDER},
+    error::AppError,
+    policy::routes::RouteClass,
+    provider::Provider,
+    session::{client_ip, validate_session, Session},
+    state::AppState,
+};
 use axum::{
-    extract::{Query, State},
-    response::{IntoResponse, Redirect, Response},
-    http::StatusCode,
+    extract::{Path, Query, State},
+    response::{Html, IntoResponse, Response},
+    http::{header, HeaderMap, HeaderValue, Method, Request, StatusCode},
     body::Body,
+    Json,
 };
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use url::Url;
 
+use self::{
+    pkce::Pkce,
+    transaction::{resolve_return_to, LoginTransaction},
+};
+
+#[derive(Debug, Deserialize)]
+pub struct LoginParams {
+    return_to: Option<String>,
+    provider: Option<String>,
+    /// Ask for the longer "remember me" session lifetime
+    #[serde(default)]
+    remember: bool,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct AuthCallback {
     code: Option<String>,
+    state: Option<String>,
     error: Option<String>,
 }
 
@@ -20,34 +50,80 @@ struct TokenRequest {
     client_id: String,
     code: String,
     redirect_uri: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code_verifier: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct RefreshRequest {
+    grant_type: String,
+    client_id: String,
+    refresh_token: String,
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[derive(Debug, Serialize)]
+struct RevokeRequest {
+    token: String,
+    client_id: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub struct TokenResponse {
-    access_token: String,
-    token_type: String,
-    expires_in: u32,
-    id_token: Option<String>,
-}
-
-pub async fn login(State(config): State<Config>) -> Redirect {
-    let mut url = Url::parse(&format!("{}/login", config.cognito_domain))
-        .expect("Failed to parse Cognito domain");
-
-    url.query_pairs_mut()
-        .append_pair("client_id", &config.cognito_client_id)
-        .append_pair("response_type", "code")
-        .append_pair("scope", "openid")
-        .append_pair(
-            "redirect_uri",
-            &format!("{}/callback", config.server_domain),
-        );
+    pub access_token: String,
+    pub token_type: String,
+    pub expires_in: u32,
+    pub id_token: Option<String>,
+    /// Only present on the code exchange, Cognito does not rotate refresh tokens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+}
+
+/// Start a login, or let the user pick a provider when there are several.
+pub async fn login(
+    State(state): State<AppState>,
+    Query(params): Query<LoginParams>,
+) -> Result<Response, AppError> {
+    let config = &state.config;
+    let provider = match (params.provider.as_deref(), state.providers.as_slice()) {
+        (Some(name), _) => state
+            .provider(name)
+            .ok_or_else(|| AppError::Auth(format!("Unknown identity provider {}", name)))?,
+        (None, [provider]) => provider,
+        (None, providers) => {
+            let page = chooser::render(providers, params.return_to.as_deref(), params.remember);
+            return Ok(Html(page).into_response());
+        }
+    };
+    let mut url = provider.authorization_url(&config.server_domain)?;
+
+    let mut code_verifier = None;
+    if config.pkce_enabled {
+        let pkce = Pkce::generate();
+        url.query_pairs_mut()
+            .append_pair("code_challenge", &pkce.challenge)
+            .append_pair("code_challenge_method", "S256");
+        code_verifier = Some(pkce.verifier);
+    }
 
-    Redirect::to(url.as_str())
+    let return_to = resolve_return_to(config, params.return_to.as_deref());
+    let mut transaction = LoginTransaction::new(provider.name(), code_verifier, return_to);
+    transaction.remember = params.remember;
+    url.query_pairs_mut().append_pair("state", &transaction.state);
+
+    Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header("location", url.as_str())
+        .header("set-cookie", transaction.to_cookie(config).to_string())
+        .body(Body::empty())
+        .map_err(|e| AppError::Internal(format!("Failed to build response: {}", e)))
 }
 
+/// Finish a login. Mounted at `/callback` for the default provider and at
+/// `/callback/{name}` for named ones.
 pub async fn callback(
-    State(config): State<Config>,
+    State(state): State<AppState>,
+    provider: Option<Path<String>>,
+    headers: HeaderMap,
     Query(params): Query<AuthCallback>,
 ) -> Result<impl IntoResponse, AppError> {
     let code = params
@@ -58,35 +134,235 @@ pub async fn callback(
         return Err(AppError::Auth(error));
     }
 
-    let token = exchange_code_for_token(&config, &code).await?;
+    let config = &state.config;
+    let name = provider.map(|Path(name)| name).unwrap_or_else(|| DEFAULT_PROVIDER.to_string());
+    let provider = state
+        .provider(&name)
+        .ok_or_else(|| AppError::Auth(format!("Unknown identity provider {}", name)))?;
+
+    // The state must round-trip unchanged, otherwise this callback was not
+    // started by this browser (login CSRF)
+    let transaction = LoginTransaction::from_headers(config, &headers)
+        .ok_or_else(|| AppError::Auth("Missing or expired login state".into()))?;
+    if params.state.as_deref() != Some(transaction.state.as_str()) {
+        tracing::warn!(target: "security_log", "OAuth state mismatch on callback");
+        return Err(AppError::Auth("Invalid state parameter".into()));
+    }
+    // A code from one provider must never be redeemed at another (mix-up attack)
+    if transaction.provider != provider.name() {
+        tracing::warn!(target: "security_log", "Login started with {} returned via {}", transaction.provider, name);
+        return Err(AppError::Auth("Invalid state parameter".into()));
+    }
+
+    if config.pkce_enabled && transaction.code_verifier.is_none() {
+        return Err(AppError::Auth("Missing PKCE verifier".into()));
+    }
+
+    let token = exchange_code_for_token(
+        config,
+        provider,
+        &code,
+        transaction.code_verifier.as_deref(),
+    )
+    .await?;
     
-    // Create a session cookie with the access token
-    let is_https = config.server_domain.starts_with("https://");
-    let cookie = crate::session::create_session_cookie(&token.access_token, is_https);
+    let cookies = crate::session::start_session(&state, provider, &token, transaction.remember, &headers).await?;
     
-    // Build response with cookie and redirect
-    let response = Response::builder()
+    // Build response with cookies and redirect
+    let mut response = Response::builder()
         .status(StatusCode::FOUND)
-        .header("location", &config.protected_website_url)
-        .header("set-cookie", cookie.to_string())
+        .header("location", &transaction.return_to);
+    for cookie in cookies {
+        response = response.header("set-cookie", cookie.to_string());
+    }
+    let response = response
+        .header("set-cookie", LoginTransaction::removal_cookie().to_string())
         .body(Body::empty())
         .map_err(|e| AppError::Internal(format!("Failed to build response: {}", e)))?;
 
     Ok(response)
 }
 
-async fn exchange_code_for_token(config: &Config, code: &str) -> Result<TokenResponse, AppError> {
-    let client = reqwest::Client::new();
-    let token_url = format!("{}/oauth2/token", config.cognito_domain);
-    
-    let response = client
-        .post(&token_url)
-        .basic_auth(&config.cognito_client_id, Some(&config.cognito_client_secret))
+/// End the session locally and at the identity provider.
+///
+/// Deletes the session, revokes its refresh token and sends the
+/// browser to the provider's sign-out page, which returns to `logout_uri`. SPAs
+/// asking for JSON get the sign-out URL in the body to navigate to
+/// themselves. Mounted for POST only, so a cross-site link or image cannot
+/// sign the user out.
+pub async fn logout(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let record = crate::session::end_session(&state, &headers)
+        .await
+        .unwrap_or_else(|e| {
+            tracing::warn!("Failed to delete session on logout: {}", e);
+            None
+        });
+    let provider = record
+        .as_ref()
+        .and_then(|record| state.provider(&record.provider))
+        .unwrap_or_else(|| state.default_provider());
+
+    if let Some(refresh_token) = record.as_ref().and_then(|record| record.refresh_token.as_deref()) {
+        // Logging out locally must not depend on the provider being reachable
+        if let Err(e) = revoke_token(provider, refresh_token).await {
+            tracing::warn!("Failed to revoke refresh token on logout: {}", e);
+        }
+    }
+
+    let url = provider.logout_url(&state.config.logout_uri);
+
+    let accept = headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()).unwrap_or("");
+    let mut response = if accept.contains("application/json") && !accept.contains("text/html") {
+        Json(json!({ "logout_url": url })).into_response()
+    } else {
+        (StatusCode::FOUND, [(header::LOCATION, url.as_str())]).into_response()
+    };
+
+    for cookie in crate::session::removal_cookies(&state.config, &headers) {
+        let value = HeaderValue::from_str(&cookie.to_string())
+            .map_err(|e| AppError::Internal(format!("Failed to build response: {}", e)))?;
+        response.headers_mut().append(header::SET_COOKIE, value);
+    }
+
+    Ok(response)
+}
+
+/// Where to send an unauthenticated request, if it is a browser navigation.
+///
+/// Page loads get redirected to the login flow with the original URL as the
+/// return target. Anything that looks like an XHR or API call gets `None` so
+/// the caller can answer with a 401 instead.
+pub fn navigation_login_url<B>(config: &Config, req: &Request<B>) -> Option<String> {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return None;
+    }
+
+    let headers = req.headers();
+    let header_str = |name| headers.get(name).and_then(|v| v.to_str().ok()).unwrap_or("");
+
+    let is_navigation = match header_str("sec-fetch-mode") {
+        "" => {
+            header_str(header::ACCEPT.as_str()).contains("text/html")
+                && !header_str("x-requested-with").eq_ignore_ascii_case("XMLHttpRequest")
+        }
+        mode => mode == "navigate",
+    };
+    if !is_navigation {
+        return None;
+    }
+
+    let return_to = req.uri().path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+    let mut url = Url::parse(&format!("{}/", config.server_domain.trim_end_matches('/'))).ok()?;
+    url.query_pairs_mut().append_pair("return_to", return_to);
+    Some(url.to_string())
+}
+
+/// Decide whether a request may reach the protected website, returning the
+/// session of the user making it, if any.
+///
+/// Public routes and passed through CORS preflights need no session,
+/// optional routes make do without one. Everything else needs a session the
+/// access policy lets through, browser navigations without one being sent to
+/// login.
+pub async fn authorize<B>(state: &AppState, req: &Request<B>) -> Result<Option<Session>, AppError> {
+    let config = &state.config;
+    let route = if config.cors_passthrough && is_preflight(req) {
+        RouteClass::Public
+    } else {
+        config.routes.classify(req.method(), req.uri().path())
+    };
+
+    match route {
+        RouteClass::Public => Ok(None),
+        RouteClass::Optional => match validate_session(state, req).await {
+            Ok(session) => Ok(Some(session)),
+            Err(AppError::Unauthorized { .. }) => Ok(None),
+            Err(e) => Err(e),
+        },
+        RouteClass::Protected => {
+            let session = validate_session(state, req)
+                .await
+                .map_err(|e| e.or_login_redirect(navigation_login_url(config, req)))?;
+
+            // Signed in is not enough for paths the access policy restricts
+            let claims = serde_json::to_value(&session.claims).unwrap_or_default();
+            if let Err(reason) = config.access_policy.check(req.method(), req.uri().path(), &claims) {
+                return Err(AppError::Forbidden {
+                    message: reason,
+                    user: session.claims.sub,
+                    client_ip: client_ip(req),
+                    path: req.uri().path().to_string(),
+                });
+            }
+            Ok(Some(session))
+        }
+    }
+}
+
+/// A CORS preflight, which browsers send without credentials.
+fn is_preflight<B>(req: &Request<B>) -> bool {
+    req.method() == Method::OPTIONS && req.headers().contains_key("access-control-request-method")
+}
+
+async fn exchange_code_for_token(
+    config: &Config,
+    provider: &Provider,
+    code: &str,
+    code_verifier: Option<&str>,
+) -> Result<TokenResponse, AppError> {
+    let response = client_request(provider, &provider.metadata.token_endpoint)
         .form(&TokenRequest {
             grant_type: "authorization_code".into(),
-            client_id: config.cognito_client_id.clone(),
+            client_id: provider.config.client_id.clone(),
             code: code.into(),
-            redirect_uri: format!("{}/callback", config.server_domain),
+            redirect_uri: provider.redirect_uri(&config.server_domain),
+            code_verifier: code_verifier.map(String::from),
+        })
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        let error = response.text().await?;
+        return Err(AppError::Auth(error));
+    }
+
+    response.json::<TokenResponse>().await.map_err(AppError::Request)
+}
+
+/// Revoke a refresh token and the access tokens issued from it.
+///
+/// A no-op for providers that do not advertise a revocation endpoint.
+async fn revoke_token(provider: &Provider, refresh_token: &str) -> Result<(), AppError> {
+    let Some(revocation_endpoint) = &provider.metadata.revocation_endpoint else {
+        return Ok(());
+    };
+
+    let response = client_request(provider, revocation_endpoint)
+        .form(&RevokeRequest {
+            token: refresh_token.into(),
+            client_id: provider.config.client_id.clone(),
+        })
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        let error = response.text().await?;
+        return Err(AppError::Auth(error));
+    }
+
+    Ok(())
+}
+
+/// Redeem a refresh token for a fresh access token.
+pub async fn refresh_tokens(provider: &Provider, refresh_token: &str) -> Result<TokenResponse, AppError> {
+    let response = client_request(provider, &provider.metadata.token_endpoint)
+        .form(&RefreshRequest {
+            grant_type: "refresh_token".into(),
+            client_id: provider.config.client_id.clone(),
+            refresh_token: refresh_token.into(),
         })
         .send()
         .await?;
@@ -99,34 +375,116 @@ async fn exchange_code_for_token(config: &Config, code: &str) -> Result<TokenRes
     response.json::<TokenResponse>().await.map_err(AppError::Request)
 }
 
+/// A POST to one of the provider's endpoints, authenticated as our client.
+fn client_request(provider: &Provider, url: &str) -> reqwest::RequestBuilder {
+    let request = reqwest::Client::new().post(url);
+    // Public clients authenticate with the PKCE verifier alone
+    if provider.config.client_secret.is_empty() {
+        return request;
+    }
+    request.basic_auth(&provider.config.client_id, Some(&provider.config.client_secret))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use axum::response::IntoResponse;
+    use crate::config::{ProviderConfig, ProviderKind};
 
     use wiremock::{
-        matchers::{method, path},
+        matchers::{body_string_contains, header_exists, method, path},
         Mock, MockServer, ResponseTemplate,
     };
 
     fn create_test_config(cognito_domain: String) -> Config {
         Config {
-            cognito_domain,
-            cognito_client_id: "test-client-id".to_string(),
-            cognito_client_secret: "test-client-secret".to_string(),
-            server_domain: "http://localhost:3000".to_string(),
-            protected_website_url: "https://test-website.com".to_string(),
-            port: 3000,
-            cors_allowed_origins: vec!["*".to_string()],
-            behind_proxy: false,
+            providers: vec![ProviderConfig::for_tests(&cognito_domain)],
+            ..Config::for_tests()
         }
     }
 
+    async fn test_state(config: Config) -> AppState {
+        AppState::for_tests(config).await
+    }
+
+    fn test_jwt(claims: serde_json::Value) -> String {
+        crate::verifier::tests::sign(&claims)
+    }
+
+    fn cognito_access_token() -> String {
+        test_jwt(serde_json::json!({
+            "sub": "user-1",
+            "iat": 1516239022,
+            "exp": 9999999999u64,
+            "iss": "https://cognito-idp.us-east-1.amazonaws.com/us-east-1_test",
+            "client_id": "test-client-id",
+            "token_use": "access",
+        }))
+    }
+
+    fn oidc_id_token(issuer: &str) -> String {
+        test_jwt(serde_json::json!({
+            "sub": "user-1",
+            "iat": 1516239022,
+            "exp": 9999999999u64,
+            "iss": issuer,
+            "aud": "oidc-client",
+        }))
+    }
+
+    /// The server-side session behind the session cookie a response sets.
+    async fn stored_session(state: &AppState, response: &Response) -> crate::store::SessionRecord {
+        let cookie = response.headers().get_all("set-cookie").iter()
+            .map(|v| cookie::Cookie::parse(v.to_str().unwrap().to_string()).unwrap())
+            .find(|c| c.name() == "authy_session")
+            .unwrap();
+        state.sessions.store().unwrap().load(cookie.value(), state.clock.now()).await.unwrap().unwrap()
+    }
+
+    /// Headers of a request belonging to a stored session with a refresh token.
+    async fn session_headers(state: &AppState) -> HeaderMap {
+        let tokens = TokenResponse {
+            access_token: cognito_access_token(),
+            token_type: "Bearer".to_string(),
+            expires_in: 3600,
+            id_token: None,
+            refresh_token: Some("test-refresh-token".to_string()),
+        };
+        let cookie = crate::session::start_session(state, state.default_provider(), &tokens, false, &HeaderMap::new())
+            .await
+            .unwrap()
+            .remove(0);
+        let mut headers = HeaderMap::new();
+        headers.insert("cookie", format!("{}={}", cookie.name(), cookie.value()).parse().unwrap());
+        headers
+    }
+
+    fn login_params(return_to: Option<&str>) -> Query<LoginParams> {
+        Query(LoginParams {
+            return_to: return_to.map(String::from),
+            provider: None,
+            remember: false,
+        })
+    }
+
+    /// Replay the cookie set by `login` the way the browser would on `/callback`.
+    fn cookie_headers(response: &Response) -> HeaderMap {
+        let set_cookie = response.headers().get("set-cookie").unwrap().to_str().unwrap();
+        let cookie = cookie::Cookie::parse(set_cookie).unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("cookie", format!("{}={}", cookie.name(), cookie.value()).parse().unwrap());
+        headers
+    }
+
+    fn location_query(response: &Response) -> std::collections::HashMap<String, String> {
+        let location = Url::parse(response.headers().get("location").unwrap().to_str().unwrap()).unwrap();
+        location.query_pairs().into_owned().collect()
+    }
+
     #[tokio::test]
     async fn test_login_redirect() {
         let config = create_test_config("https://test.auth.region.amazoncognito.com".to_string());
 
-        let response = login(State(config)).await.into_response();
+        let response = login(State(test_state(config).await), login_params(None)).await.unwrap();
         let location = response.headers().get("location").unwrap().to_str().unwrap();
         
         assert!(location.starts_with("https://test.auth.region.amazoncognito.com/login"));
@@ -136,29 +494,175 @@ mod tests {
         assert!(location.contains("redirect_uri=http%3A%2F%2Flocalhost%3A3000%2Fcallback"));
     }
 
+    #[tokio::test]
+    async fn test_login_transaction_cookie() {
+        let config = create_test_config("https://test.auth.region.amazoncognito.com".to_string());
+
+        let response = login(State(test_state(config.clone()).await), login_params(Some("/reports/42"))).await.unwrap();
+        let query = location_query(&response);
+        assert_eq!(query["code_challenge_method"], "S256");
+
+        // The verifier and return target only come back through the signed cookie
+        let transaction = LoginTransaction::from_headers(&config, &cookie_headers(&response)).unwrap();
+        assert_eq!(transaction.state, query["state"]);
+        assert_eq!(pkce::challenge_for(transaction.code_verifier.as_deref().unwrap()), query["code_challenge"]);
+        assert_eq!(transaction.return_to, "https://test-website.com/reports/42");
+    }
+
+    #[tokio::test]
+    async fn test_login_pkce_disabled() {
+        let mut config = create_test_config("https://test.auth.region.amazoncognito.com".to_string());
+        config.pkce_enabled = false;
+
+        let response = login(State(test_state(config.clone()).await), login_params(None)).await.unwrap();
+        let query = location_query(&response);
+        assert!(!query.contains_key("code_challenge"));
+        assert!(query.contains_key("state"));
+
+        let transaction = LoginTransaction::from_headers(&config, &cookie_headers(&response)).unwrap();
+        assert_eq!(transaction.code_verifier, None);
+    }
+
+    #[test]
+    fn test_navigation_login_url() {
+        let config = create_test_config("https://test.auth.amazoncognito.com".to_string());
+
+        let req = Request::builder()
+            .uri("/reports/42?tab=1")
+            .header("accept", "text/html,application/xhtml+xml")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            navigation_login_url(&config, &req).as_deref(),
+            Some("http://localhost:3000/?return_to=%2Freports%2F42%3Ftab%3D1")
+        );
+
+        // Fetch metadata wins over the Accept header
+        let req = Request::builder()
+            .uri("/")
+            .header("sec-fetch-mode", "navigate")
+            .body(Body::empty())
+            .unwrap();
+        assert!(navigation_login_url(&config, &req).is_some());
+    }
+
+    #[test]
+    fn test_navigation_login_url_api_requests() {
+        let config = create_test_config("https://test.auth.amazoncognito.com".to_string());
+
+        let requests = [
+            Request::builder().uri("/api").header("accept", "application/json"),
+            Request::builder().uri("/api").header("accept", "text/html").header("x-requested-with", "XMLHttpRequest"),
+            Request::builder().uri("/api").header("accept", "text/html").header("sec-fetch-mode", "cors"),
+            Request::builder().method(Method::POST).uri("/form").header("accept", "text/html"),
+        ];
+        for req in requests {
+            let req = req.body(Body::empty()).unwrap();
+            assert!(navigation_login_url(&config, &req).is_none(), "{:?}", req);
+        }
+    }
+
     #[tokio::test]
     async fn test_callback_no_code() {
         let config = create_test_config("https://test.auth.amazoncognito.com".to_string());
 
         let params = AuthCallback {
             code: None,
+            state: None,
             error: None,
         };
 
-        let result = callback(State(config), Query(params)).await;
+        let result = callback(State(test_state(config).await), None, HeaderMap::new(), Query(params)).await;
         assert!(matches!(result, Err(AppError::Auth(msg)) if msg == "No authorization code provided"));
     }
 
+    #[tokio::test]
+    async fn test_callback_missing_login_cookie() {
+        let config = create_test_config("https://test.auth.amazoncognito.com".to_string());
+
+        let params = AuthCallback {
+            code: Some("test-code".to_string()),
+            state: Some("some-state".to_string()),
+            error: None,
+        };
+
+        let result = callback(State(test_state(config).await), None, HeaderMap::new(), Query(params)).await;
+        assert!(matches!(result, Err(AppError::Auth(msg)) if msg == "Missing or expired login state"));
+    }
+
+    #[tokio::test]
+    async fn test_callback_state_mismatch() {
+        let config = create_test_config("https://test.auth.amazoncognito.com".to_string());
+        let response = login(State(test_state(config.clone()).await), login_params(None)).await.unwrap();
+
+        let params = AuthCallback {
+            code: Some("test-code".to_string()),
+            state: Some("attacker-state".to_string()),
+            error: None,
+        };
+
+        let result = callback(State(test_state(config).await), None, cookie_headers(&response), Query(params)).await;
+        assert!(matches!(result, Err(AppError::Auth(msg)) if msg == "Invalid state parameter"));
+    }
+
+    #[tokio::test]
+    async fn test_callback_redirects_to_return_target() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("POST"))
+            .and(path("/oauth2/token"))
+            .and(body_string_contains("code_verifier="))
+            .respond_with(ResponseTemplate::new(200).set_body_json(TokenResponse {
+                access_token: cognito_access_token(),
+                token_type: "Bearer".to_string(),
+                expires_in: 3600,
+                id_token: None,
+                refresh_token: Some("test-refresh-token".to_string()),
+            }))
+            .mount(&mock_server)
+            .await;
+
+        let state = test_state(create_test_config(mock_server.uri())).await;
+        let response = login(State(state.clone()), login_params(Some("/reports/42"))).await.unwrap();
+
+        let params = AuthCallback {
+            code: Some("test-code".to_string()),
+            state: Some(location_query(&response)["state"].clone()),
+            error: None,
+        };
+
+        let response = callback(State(state.clone()), None, cookie_headers(&response), Query(params))
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert_eq!(response.headers().get("location").unwrap(), "https://test-website.com/reports/42");
+
+        let cookies: Vec<_> = response.headers().get_all("set-cookie").iter()
+            .map(|v| v.to_str().unwrap().to_string())
+            .collect();
+        assert_eq!(cookies.len(), 2);
+        assert!(cookies.iter().any(|c| c.starts_with("authy_login=;")));
+
+        // The tokens stay server-side
+        let record = stored_session(&state, &response).await;
+        assert_eq!(record.provider, "default");
+        assert_eq!(record.access_token, cognito_access_token());
+        assert_eq!(record.refresh_token.as_deref(), Some("test-refresh-token"));
+        assert!(cookies.iter().all(|c| !c.contains("test-refresh-token")));
+    }
+
     #[tokio::test]
     async fn test_callback_with_error() {
         let config = create_test_config("https://test.auth.amazoncognito.com".to_string());
 
         let params = AuthCallback {
             code: Some("test-code".to_string()),
+            state: None,
             error: Some("access_denied".to_string()),
         };
 
-        let result = callback(State(config), Query(params)).await;
+        let result = callback(State(test_state(config).await), None, HeaderMap::new(), Query(params)).await;
         assert!(matches!(result, Err(AppError::Auth(msg)) if msg == "access_denied"));
     }
 
@@ -171,20 +675,142 @@ mod tests {
             token_type: "Bearer".to_string(),
             expires_in: 3600,
             id_token: Some("test-id-token".to_string()),
+            refresh_token: Some("test-refresh-token".to_string()),
         };
 
         Mock::given(method("POST"))
             .and(path("/oauth2/token"))
+            .and(header_exists("authorization"))
+            .and(body_string_contains("code_verifier=test-verifier"))
             .respond_with(ResponseTemplate::new(200).set_body_json(&token_response))
             .mount(&mock_server)
             .await;
 
-        let config = create_test_config(mock_server.uri());
+        let state = test_state(create_test_config(mock_server.uri())).await;
 
-        let result = exchange_code_for_token(&config, "test-code").await.unwrap();
+        let result = exchange_code_for_token(&state.config, state.default_provider(), "test-code", Some("test-verifier"))
+            .await
+            .unwrap();
         assert_eq!(result, token_response);
     }
 
+    #[tokio::test]
+    async fn test_exchange_code_public_client() {
+        let mock_server = MockServer::start().await;
+
+        let token_response = TokenResponse {
+            access_token: "test-access-token".to_string(),
+            token_type: "Bearer".to_string(),
+            expires_in: 3600,
+            id_token: None,
+            refresh_token: None,
+        };
+
+        Mock::given(method("POST"))
+            .and(path("/oauth2/token"))
+            .and(body_string_contains("code_verifier=test-verifier"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(&token_response))
+            .mount(&mock_server)
+            .await;
+
+        let mut config = create_test_config(mock_server.uri());
+        config.providers[0].client_secret = String::new();
+        let state = test_state(config).await;
+
+        let result = exchange_code_for_token(&state.config, state.default_provider(), "test-code", Some("test-verifier"))
+            .await
+            .unwrap();
+        assert_eq!(result, token_response);
+
+        // No client secret means no basic auth
+        let requests = mock_server.received_requests().await.unwrap();
+        assert!(requests[0].headers.iter().all(|(name, _)| name.as_str() != "authorization"));
+    }
+
+    #[tokio::test]
+    async fn test_refresh_tokens() {
+        let mock_server = MockServer::start().await;
+
+        let token_response = TokenResponse {
+            access_token: "new-access-token".to_string(),
+            token_type: "Bearer".to_string(),
+            expires_in: 3600,
+            id_token: Some("new-id-token".to_string()),
+            refresh_token: None,
+        };
+
+        Mock::given(method("POST"))
+            .and(path("/oauth2/token"))
+            .and(body_string_contains("grant_type=refresh_token"))
+            .and(body_string_contains("refresh_token=test-refresh-token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(&token_response))
+            .mount(&mock_server)
+            .await;
+
+        let state = test_state(create_test_config(mock_server.uri())).await;
+
+        let result = refresh_tokens(state.default_provider(), "test-refresh-token").await.unwrap();
+        assert_eq!(result, token_response);
+    }
+
+    #[tokio::test]
+    async fn test_logout_revokes_and_redirects() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("POST"))
+            .and(path("/oauth2/revoke"))
+            .and(body_string_contains("token=test-refresh-token"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let state = test_state(create_test_config(mock_server.uri())).await;
+        let headers = session_headers(&state).await;
+
+        let response = logout(State(state.clone()), headers.clone()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FOUND);
+
+        let location = response.headers().get("location").unwrap().to_str().unwrap();
+        assert!(location.starts_with(&format!("{}/logout", mock_server.uri())));
+        assert!(location.contains("client_id=test-client-id"));
+        assert!(location.contains("logout_uri=http%3A%2F%2Flocalhost%3A3000%2F"));
+
+        let cleared: Vec<_> = response.headers().get_all("set-cookie").iter()
+            .map(|v| cookie::Cookie::parse(v.to_str().unwrap().to_string()).unwrap())
+            .collect();
+        assert_eq!(cleared.len(), 1);
+        assert!(cleared[0].name() == "authy_session" && cleared[0].value().is_empty());
+
+        // The session is gone server-side too
+        assert!(crate::session::end_session(&state, &headers).await.unwrap().is_none());
+
+        mock_server.verify().await;
+    }
+
+    #[tokio::test]
+    async fn test_logout_json_survives_revocation_failure() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("POST"))
+            .and(path("/oauth2/revoke"))
+            .respond_with(ResponseTemplate::new(400).set_body_string("unsupported_token_type"))
+            .mount(&mock_server)
+            .await;
+
+        let state = test_state(create_test_config(mock_server.uri())).await;
+        let mut headers = session_headers(&state).await;
+        headers.insert("accept", "application/json".parse().unwrap());
+
+        let response = logout(State(state), headers).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get_all("set-cookie").iter().count(), 1);
+
+        let body = axum::body::to_bytes(response.into_body(), 1024).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(body["logout_url"].as_str().unwrap().contains("/logout?client_id=test-client-id"));
+    }
+
     #[tokio::test]
     async fn test_exchange_code_failure() {
         let mock_server = MockServer::start().await;
@@ -195,9 +821,201 @@ mod tests {
             .mount(&mock_server)
             .await;
 
-        let config = create_test_config(mock_server.uri());
+        let state = test_state(create_test_config(mock_server.uri())).await;
 
-        let result = exchange_code_for_token(&config, "invalid-code").await;
+        let result = exchange_code_for_token(&state.config, state.default_provider(), "invalid-code", None).await;
         assert!(matches!(result, Err(AppError::Auth(msg)) if msg == "invalid_grant"));
     }
+
+    #[tokio::test]
+    async fn test_oidc_login_and_callback() {
+        let mock_server = MockServer::start().await;
+        let issuer = mock_server.uri();
+
+        Mock::given(method("GET"))
+            .and(path("/.well-known/openid-configuration"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "issuer": issuer,
+                "authorization_endpoint": format!("{}/authorize", issuer),
+                "token_endpoint": format!("{}/token", issuer),
+                "jwks_uri": format!("{}/jwks", issuer)
+            })))
+            .mount(&mock_server)
+            .await;
+
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .and(body_string_contains("client_id=oidc-client"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(TokenResponse {
+                access_token: "opaque-access-token".to_string(),
+                token_type: "Bearer".to_string(),
+                expires_in: 300,
+                id_token: Some(oidc_id_token(&issuer)),
+                refresh_token: None,
+            }))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let config = Config {
+            providers: vec![oidc_provider("default", &issuer)],
+            ..Config::for_tests()
+        };
+        let state = test_state(config).await;
+
+        let response = login(State(state.clone()), login_params(None)).await.unwrap();
+        let location = response.headers().get("location").unwrap().to_str().unwrap();
+        assert!(location.starts_with(&format!("{}/authorize?", issuer)));
+        let query = location_query(&response);
+        assert_eq!(query["client_id"], "oidc-client");
+        assert_eq!(query["scope"], "openid profile email");
+
+        let params = AuthCallback {
+            code: Some("test-code".to_string()),
+            state: Some(query["state"].clone()),
+            error: None,
+        };
+        let response = callback(State(state.clone()), None, cookie_headers(&response), Query(params))
+            .await
+            .unwrap()
+            .into_response();
+
+        // The ID token is what the session is validated with
+        let record = stored_session(&state, &response).await;
+        assert_eq!(record.claims.aud, Some(crate::session::Audience::One("oidc-client".to_string())));
+        assert_eq!(record.id_token, Some(oidc_id_token(&issuer)));
+
+        mock_server.verify().await;
+    }
+
+    fn oidc_provider(name: &str, issuer: &str) -> ProviderConfig {
+        ProviderConfig {
+            name: name.to_string(),
+            label: "Employees & <Staff>".to_string(),
+            kind: ProviderKind::Oidc { issuer: issuer.to_string() },
+            client_id: "oidc-client".to_string(),
+            client_secret: String::new(),
+            scopes: "openid profile email".to_string(),
+        }
+    }
+
+    /// A Cognito pool as the default provider plus an OIDC provider named `corp`.
+    async fn multi_provider_state(mock_server: &MockServer) -> AppState {
+        Mock::given(method("GET"))
+            .and(path("/.well-known/openid-configuration"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "issuer": mock_server.uri(),
+                "authorization_endpoint": format!("{}/authorize", mock_server.uri()),
+                "token_endpoint": format!("{}/token", mock_server.uri()),
+                "jwks_uri": format!("{}/jwks", mock_server.uri())
+            })))
+            .mount(mock_server)
+            .await;
+
+        test_state(Config {
+            providers: vec![
+                ProviderConfig::for_tests("https://test.auth.amazoncognito.com"),
+                oidc_provider("corp", &mock_server.uri()),
+            ],
+            ..Config::for_tests()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_login_provider_chooser() {
+        let mock_server = MockServer::start().await;
+        let state = multi_provider_state(&mock_server).await;
+
+        let response = login(State(state), login_params(Some("/reports/42"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get("set-cookie").is_none());
+
+        let body = axum::body::to_bytes(response.into_body(), 64 * 1024).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains(r#"<a href="/?provider=default&amp;return_to=%2Freports%2F42">Cognito</a>"#));
+        assert!(body.contains(r#"<a href="/?provider=corp&amp;return_to=%2Freports%2F42">Employees &amp; &lt;Staff&gt;</a>"#));
+    }
+
+    #[tokio::test]
+    async fn test_login_with_named_provider() {
+        let mock_server = MockServer::start().await;
+        let state = multi_provider_state(&mock_server).await;
+
+        let params = Query(LoginParams {
+            return_to: None,
+            provider: Some("corp".to_string()),
+            remember: false,
+        });
+        let response = login(State(state.clone()), params).await.unwrap();
+        let location = response.headers().get("location").unwrap().to_str().unwrap();
+        assert!(location.starts_with(&format!("{}/authorize?", mock_server.uri())));
+        assert_eq!(location_query(&response)["redirect_uri"], "http://localhost:3000/callback/corp");
+
+        let transaction = LoginTransaction::from_headers(&state.config, &cookie_headers(&response)).unwrap();
+        assert_eq!(transaction.provider, "corp");
+
+        let params = Query(LoginParams {
+            return_to: None,
+            provider: Some("nope".to_string()),
+            remember: false,
+        });
+        let result = login(State(state), params).await;
+        assert!(matches!(result, Err(AppError::Auth(msg)) if msg == "Unknown identity provider nope"));
+    }
+
+    #[tokio::test]
+    async fn test_callback_records_provider() {
+        let mock_server = MockServer::start().await;
+        let state = multi_provider_state(&mock_server).await;
+
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .and(body_string_contains("redirect_uri=http%3A%2F%2Flocalhost%3A3000%2Fcallback%2Fcorp"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(TokenResponse {
+                access_token: "opaque-access-token".to_string(),
+                token_type: "Bearer".to_string(),
+                expires_in: 300,
+                id_token: Some(oidc_id_token(&mock_server.uri())),
+                refresh_token: None,
+            }))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let params = Query(LoginParams {
+            return_to: None,
+            provider: Some("corp".to_string()),
+            remember: false,
+        });
+        let login_response = login(State(state.clone()), params).await.unwrap();
+        let callback_params = || AuthCallback {
+            code: Some("test-code".to_string()),
+            state: Some(location_query(&login_response)["state"].clone()),
+            error: None,
+        };
+
+        // Returning through another provider's callback is refused
+        let result = callback(
+            State(state.clone()),
+            None,
+            cookie_headers(&login_response),
+            Query(callback_params()),
+        )
+        .await;
+        assert!(matches!(result, Err(AppError::Auth(msg)) if msg == "Invalid state parameter"));
+
+        let response = callback(
+            State(state.clone()),
+            Some(Path("corp".to_string())),
+            cookie_headers(&login_response),
+            Query(callback_params()),
+        )
+        .await
+        .unwrap()
+        .into_response();
+        assert_eq!(stored_session(&state, &response).await.provider, "corp");
+
+        mock_server.verify().await;
+    }
 }
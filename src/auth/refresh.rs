@@ -0,0 +1,148 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::Mutex as AsyncMutex;
+
+use crate::{error::AppError, provider::Provider};
+
+use super::TokenResponse;
+
+/// How long a completed refresh is handed out to requests that raced it.
+const REUSE_WINDOW: Duration = Duration::from_secs(30);
+
+#[derive(Default)]
+struct Slot {
+    result: Option<(Instant, TokenResponse)>,
+}
+
+/// Serializes refreshes per refresh token.
+///
+/// A page load fires many requests at once, and all of them notice the
+/// expired access token together. The first one redeems the refresh token;
+/// the rest wait on the same slot and reuse its result instead of sending
+/// their own request to the token endpoint.
+#[derive(Clone, Default)]
+pub struct TokenRefresher {
+    slots: Arc<Mutex<HashMap<String, Arc<AsyncMutex<Slot>>>>>,
+}
+
+impl TokenRefresher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn refresh(&self, provider: &Provider, refresh_token: &str) -> Result<TokenResponse, AppError> {
+        let slot = {
+            let mut slots = self.slots.lock().unwrap();
+            // Drop slots nobody is using any more
+            slots.retain(|_, slot| {
+                Arc::strong_count(slot) > 1
+                    || slot.try_lock().map_or(true, |s| {
+                        s.result.as_ref().is_some_and(|(at, _)| at.elapsed() < REUSE_WINDOW)
+                    })
+            });
+            slots.entry(refresh_token.to_owned()).or_default().clone()
+        };
+
+        let mut slot = slot.lock().await;
+        if let Some((at, tokens)) = &slot.result {
+            if at.elapsed() < REUSE_WINDOW {
+                return Ok(tokens.clone());
+            }
+        }
+
+        let tokens = super::refresh_tokens(provider, refresh_token).await?;
+        slot.result = Some((Instant::now(), tokens.clone()));
+        Ok(tokens)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ProviderConfig;
+    use wiremock::{
+        matchers::{body_string_contains, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    async fn create_test_provider(cognito_domain: String) -> Provider {
+        Provider::new(ProviderConfig::for_tests(&cognito_domain), Duration::from_secs(3600))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_refreshes_share_one_request() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("POST"))
+            .and(path("/oauth2/token"))
+            .and(body_string_contains("refresh_token=shared-token"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({
+                        "access_token": "new-access-token",
+                        "token_type": "Bearer",
+                        "expires_in": 3600
+                    }))
+                    .set_delay(Duration::from_millis(100)),
+            )
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let provider = create_test_provider(mock_server.uri()).await;
+        let refresher = TokenRefresher::new();
+
+        let results = refresh_concurrently(&refresher, &provider, 10).await;
+        for result in results {
+            assert_eq!(result.unwrap().access_token, "new-access-token");
+        }
+
+        mock_server.verify().await;
+    }
+
+    #[tokio::test]
+    async fn test_failed_refresh_is_not_cached() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("POST"))
+            .and(path("/oauth2/token"))
+            .respond_with(ResponseTemplate::new(400).set_body_string("invalid_grant"))
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+
+        let provider = create_test_provider(mock_server.uri()).await;
+        let refresher = TokenRefresher::new();
+
+        assert!(refresher.refresh(&provider, "revoked-token").await.is_err());
+        assert!(refresher.refresh(&provider, "revoked-token").await.is_err());
+
+        mock_server.verify().await;
+    }
+
+    async fn refresh_concurrently(
+        refresher: &TokenRefresher,
+        provider: &Provider,
+        count: usize,
+    ) -> Vec<Result<TokenResponse, AppError>> {
+        let handles: Vec<_> = (0..count)
+            .map(|_| {
+                let refresher = refresher.clone();
+                let provider = provider.clone();
+                tokio::spawn(async move { refresher.refresh(&provider, "shared-token").await })
+            })
+            .collect();
+
+        let mut results = Vec::new();
+        for handle in handles {
+            results.push(handle.await.unwrap());
+        }
+        results
+    }
+}
@@ -0,0 +1,186 @@
+use axum::http::HeaderMap;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use cookie::{time::Duration, Cookie, CookieJar, SameSite};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::config::Config;
+
+use super::pkce::random_urlsafe;
+
+pub const LOGIN_COOKIE_NAME: &str = "authy_login";
+
+/// Everything `callback` needs to finish a login started by `login`.
+///
+/// Travels in a signed, short-lived cookie so nothing is kept server-side
+/// between the redirect to the identity provider and its return.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct LoginTransaction {
+    pub state: String,
+    /// Provider the login was started with
+    pub provider: String,
+    pub code_verifier: Option<String>,
+    pub return_to: String,
+    /// Whether the user asked to be remembered
+    #[serde(default)]
+    pub remember: bool,
+}
+
+impl LoginTransaction {
+    pub fn new(provider: &str, code_verifier: Option<String>, return_to: String) -> Self {
+        LoginTransaction {
+            state: random_urlsafe(32),
+            provider: provider.to_string(),
+            code_verifier,
+            return_to,
+            remember: false,
+        }
+    }
+
+    pub fn to_cookie(&self, config: &Config) -> Cookie<'static> {
+        let value = URL_SAFE_NO_PAD.encode(
+            serde_json::to_vec(self).expect("login transaction is always serializable"),
+        );
+
+        let mut cookie = Cookie::new(LOGIN_COOKIE_NAME, value);
+        cookie.set_path("/callback");
+        cookie.set_http_only(true);
+        cookie.set_same_site(Some(SameSite::Lax));
+        cookie.set_secure(config.is_https());
+        cookie.set_max_age(Duration::minutes(10));
+
+        let mut jar = CookieJar::new();
+        jar.signed_mut(&config.cookie_key()).add(cookie);
+        jar.get(LOGIN_COOKIE_NAME).cloned().expect("cookie was just added")
+    }
+
+    /// Read the transaction back, rejecting missing, tampered or malformed cookies.
+    pub fn from_headers(config: &Config, headers: &HeaderMap) -> Option<Self> {
+        let jar = crate::session::parse_cookies(headers)?;
+        let cookie = jar.signed(&config.cookie_key()).get(LOGIN_COOKIE_NAME)?;
+        let bytes = URL_SAFE_NO_PAD.decode(cookie.value()).ok()?;
+        serde_json::from_slice(&bytes).ok()
+    }
+
+    pub fn removal_cookie() -> Cookie<'static> {
+        let mut cookie = Cookie::new(LOGIN_COOKIE_NAME, "");
+        cookie.set_path("/callback");
+        cookie.make_removal();
+        cookie
+    }
+}
+
+/// Resolve where to send the user after login.
+///
+/// Relative paths are mapped onto the protected website the same way the
+/// proxy maps request paths. Absolute URLs are only accepted when they
+/// point at the protected website or at authy itself, so the parameter
+/// cannot be used as an open redirect.
+pub fn resolve_return_to(config: &Config, return_to: Option<&str>) -> String {
+    let default = config.protected_website_url.clone();
+    let Some(target) = return_to.filter(|t| !t.is_empty()) else {
+        return default;
+    };
+
+    if target.starts_with('/') {
+        // `//host` and `/\host` are treated as absolute by browsers
+        if target.starts_with("//") || target.starts_with("/\\") {
+            tracing::warn!(target: "security_log", "Rejected return target {}", target);
+            return default;
+        }
+        return format!("{}{}", config.protected_website_url.trim_end_matches('/'), target);
+    }
+
+    let allowed = |candidate: &Url| {
+        [&config.protected_website_url, &config.server_domain]
+            .iter()
+            .filter_map(|base| Url::parse(base).ok())
+            .any(|base| base.origin() == candidate.origin())
+    };
+
+    match Url::parse(target) {
+        Ok(url) if allowed(&url) => url.to_string(),
+        _ => {
+            tracing::warn!(target: "security_log", "Rejected return target {}", target);
+            default
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_test_config() -> Config {
+        Config {
+            server_domain: "https://auth.example.com".to_string(),
+            protected_website_url: "https://app.example.com/".to_string(),
+            ..Config::for_tests()
+        }
+    }
+
+    #[test]
+    fn test_cookie_round_trip() {
+        let config = create_test_config();
+        let transaction = LoginTransaction::new("default", Some("verifier".into()), "https://app.example.com/x".into());
+        let cookie = transaction.to_cookie(&config);
+        assert_eq!(cookie.path(), Some("/callback"));
+        assert_eq!(cookie.secure(), Some(true));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("cookie", format!("{}={}", cookie.name(), cookie.value()).parse().unwrap());
+        assert_eq!(LoginTransaction::from_headers(&config, &headers), Some(transaction));
+    }
+
+    #[test]
+    fn test_cookie_rejects_tampering() {
+        let config = create_test_config();
+        let cookie = LoginTransaction::new("default", None, "/".into()).to_cookie(&config);
+
+        // Signed with a different secret
+        let mut other = create_test_config();
+        other.cookie_secret = "another-secret".to_string();
+        let mut headers = HeaderMap::new();
+        headers.insert("cookie", format!("{}={}", cookie.name(), cookie.value()).parse().unwrap());
+        assert!(LoginTransaction::from_headers(&other, &headers).is_none());
+
+        // Unsigned value
+        headers.insert("cookie", format!("{}=forged", LOGIN_COOKIE_NAME).parse().unwrap());
+        assert!(LoginTransaction::from_headers(&config, &headers).is_none());
+    }
+
+    #[test]
+    fn test_resolve_return_to() {
+        let config = create_test_config();
+
+        assert_eq!(resolve_return_to(&config, None), "https://app.example.com/");
+        assert_eq!(
+            resolve_return_to(&config, Some("/reports/42?tab=1")),
+            "https://app.example.com/reports/42?tab=1"
+        );
+        assert_eq!(
+            resolve_return_to(&config, Some("https://app.example.com/reports/42")),
+            "https://app.example.com/reports/42"
+        );
+        assert_eq!(
+            resolve_return_to(&config, Some("https://auth.example.com/reports/42")),
+            "https://auth.example.com/reports/42"
+        );
+    }
+
+    #[test]
+    fn test_resolve_return_to_rejects_open_redirects() {
+        let config = create_test_config();
+
+        for target in [
+            "https://evil.example.com/",
+            "//evil.example.com/",
+            "/\\evil.example.com/",
+            "http://app.example.com/",
+            "javascript:alert(1)",
+            "reports/42",
+        ] {
+            assert_eq!(resolve_return_to(&config, Some(target)), "https://app.example.com/", "{}", target);
+        }
+    }
+}
@@ -0,0 +1,49 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Source of the current Unix time for token and session expiry checks.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> u64;
+}
+
+/// The host's wall clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use super::*;
+
+    /// The wall-clock time, for tests that build tokens and records.
+    pub fn now() -> u64 {
+        SystemClock.now()
+    }
+
+    /// A clock that only moves when told to, starting at the current time.
+    pub struct MockClock(AtomicU64);
+
+    impl MockClock {
+        pub fn new() -> Self {
+            MockClock(AtomicU64::new(now()))
+        }
+
+        pub fn advance(&self, secs: u64) {
+            self.0.fetch_add(secs, Ordering::SeqCst);
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> u64 {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+}
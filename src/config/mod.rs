@@ -1,39 +1,577 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use cookie::{Key, SameSite};
+use rand::{distributions::Alphanumeric, Rng};
 use serde::Deserialize;
-use std::env;
+use sha2::{Digest, Sha512};
+use std::{env, fmt};
+
+use crate::{
+    identity::IdentityHeaders,
+    policy::{routes::RouteTable, AccessPolicy},
+};
+
+/// Name of the provider configured through the unprefixed variables.
+pub const DEFAULT_PROVIDER: &str = "default";
+
+/// Where users sign in.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub enum ProviderKind {
+    /// A Cognito user pool, whose endpoints are known without discovery
+    Cognito {
+        domain: String,
+        region: String,
+        user_pool_id: String,
+        /// Federated IdP to send users to directly, skipping the hosted UI
+        identity_provider: Option<String>,
+    },
+    /// Any OpenID Connect provider, found through its discovery document
+    Oidc { issuer: String },
+}
+
+impl ProviderKind {
+    /// Expected `iss` of the provider's tokens.
+    ///
+    /// For Cognito this is derived from the user pool, not the hosted UI
+    /// domain.
+    pub fn issuer(&self) -> String {
+        match self {
+            ProviderKind::Cognito { region, user_pool_id, .. } => {
+                format!("https://cognito-idp.{}.amazonaws.com/{}", region, user_pool_id)
+            }
+            ProviderKind::Oidc { issuer } => issuer.trim_end_matches('/').to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ProviderConfig {
+    /// Identifies the provider in URLs and in the session
+    pub name: String,
+    /// Shown on the provider chooser page
+    pub label: String,
+    pub kind: ProviderKind,
+    pub client_id: String,
+    /// Empty for public clients
+    pub client_secret: String,
+    pub scopes: String,
+}
+
+impl ProviderConfig {
+    /// Read the providers named in `PROVIDERS`, or the single provider
+    /// configured by the unprefixed variables when it is not set.
+    ///
+    /// A provider named `corp` reads `CORP_OIDC_ISSUER`, `CORP_COGNITO_DOMAIN`
+    /// and so on.
+    fn all_from_env() -> Result<Vec<Self>, env::VarError> {
+        let Ok(names) = env::var("PROVIDERS") else {
+            return Ok(vec![Self::from_env(DEFAULT_PROVIDER, "")?]);
+        };
+
+        names
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                let prefix = format!("{}_", name.to_uppercase().replace('-', "_"));
+                Self::from_env(name, &prefix)
+            })
+            .collect()
+    }
+
+    fn from_env(name: &str, prefix: &str) -> Result<Self, env::VarError> {
+        let var = |key: &str| env::var(format!("{}{}", prefix, key));
+        let scopes = var("OAUTH_SCOPES").unwrap_or_else(|_| "openid".to_string());
+
+        if let Ok(issuer) = var("OIDC_ISSUER") {
+            return Ok(ProviderConfig {
+                name: name.to_string(),
+                label: var("LABEL").unwrap_or_else(|_| name.to_string()),
+                kind: ProviderKind::Oidc { issuer },
+                client_id: var("OIDC_CLIENT_ID")?,
+                client_secret: var("OIDC_CLIENT_SECRET").unwrap_or_default(),
+                scopes,
+            });
+        }
+
+        let user_pool_id = var("COGNITO_USER_POOL_ID")?;
+        // Pool IDs are prefixed with their region, e.g. `us-east-1_abcd1234`
+        let region = var("COGNITO_REGION").unwrap_or_else(|_| {
+            user_pool_id.split('_').next().unwrap_or_default().to_string()
+        });
+
+        Ok(ProviderConfig {
+            name: name.to_string(),
+            label: var("LABEL").unwrap_or_else(|_| name.to_string()),
+            kind: ProviderKind::Cognito {
+                domain: var("COGNITO_DOMAIN")?,
+                region,
+                user_pool_id,
+                identity_provider: var("COGNITO_IDENTITY_PROVIDER").ok(),
+            },
+            client_id: var("COGNITO_CLIENT_ID")?,
+            // Public app clients have no secret
+            client_secret: var("COGNITO_CLIENT_SECRET").unwrap_or_default(),
+            scopes,
+        })
+    }
+
+    /// Path the provider redirects back to after sign-in.
+    ///
+    /// The unnamed provider keeps the bare `/callback` so existing app client
+    /// registrations keep working.
+    pub fn callback_path(&self) -> String {
+        if self.name == DEFAULT_PROVIDER {
+            "/callback".to_string()
+        } else {
+            format!("/callback/{}", self.name)
+        }
+    }
+}
+
+/// Where server-side sessions are kept.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub enum SessionStoreKind {
+    Memory,
+    Sqlite { path: String },
+    /// Sessions sealed into the cookie itself, the first key encrypts and
+    /// all of them decrypt
+    Cookie { keys: Vec<SessionKey> },
+}
+
+impl SessionStoreKind {
+    fn from_env(errors: &mut Vec<String>) -> Self {
+        match env::var("SESSION_STORE").unwrap_or_default().to_lowercase().as_str() {
+            "sqlite" => SessionStoreKind::Sqlite {
+                path: env::var("SESSION_DB_PATH").unwrap_or_else(|_| "authy-sessions.db".to_string()),
+            },
+            "cookie" => {
+                let entries = env::var("SESSION_KEYS").unwrap_or_default();
+                let entries: Vec<_> = entries.split(',').map(str::trim).filter(|entry| !entry.is_empty()).collect();
+                if entries.is_empty() {
+                    errors.push("SESSION_STORE is cookie but SESSION_KEYS is empty".into());
+                }
+                let mut keys = Vec::new();
+                for (idx, entry) in entries.into_iter().enumerate() {
+                    match SessionKey::parse(entry) {
+                        Ok(key) => keys.push(key),
+                        // The entry holds a secret, name it by position
+                        Err(e) => errors.push(format!("SESSION_KEYS entry {}: {}", idx + 1, e)),
+                    }
+                }
+                SessionStoreKind::Cookie { keys }
+            }
+            "" | "memory" => SessionStoreKind::Memory,
+            other => {
+                errors.push(format!("Unknown SESSION_STORE {:?}, expected memory, sqlite or cookie", other));
+                SessionStoreKind::Memory
+            }
+        }
+    }
+}
+
+/// A key for sealing cookie sessions, named so a cookie can say which key
+/// sealed it while keys are rotated.
+#[derive(Clone, Deserialize, PartialEq)]
+pub struct SessionKey {
+    pub id: String,
+    pub key: [u8; 32],
+}
+
+impl SessionKey {
+    /// Parse `<id>:<base64 key>`, the ID being limited to characters that
+    /// are safe in a cookie value.
+    fn parse(entry: &str) -> Result<Self, String> {
+        let (id, key) = entry.split_once(':').ok_or("expected <id>:<base64 32 byte key>")?;
+        let id_is_safe = !id.is_empty()
+            && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !id_is_safe {
+            return Err(format!("invalid key ID {:?}, use letters, digits, - and _", id));
+        }
+
+        let key = STANDARD.decode(key.trim()).map_err(|_| "key is not valid base64")?;
+        let key = key.try_into().map_err(|key: Vec<u8>| format!("key is {} bytes, expected 32", key.len()))?;
+        Ok(SessionKey { id: id.to_string(), key })
+    }
+}
+
+impl fmt::Debug for SessionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionKey")
+            .field("id", &self.id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// How long sessions last, in seconds.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct SessionLifetime {
+    /// End sessions unused for this long
+    pub idle_timeout: Option<u64>,
+    /// Make users sign in again after this long, however active
+    pub max_age: u64,
+    /// Longer maximum for sessions started with `remember=true`, which is
+    /// ignored unless this is set
+    pub remember_me_max_age: Option<u64>,
+}
+
+impl SessionLifetime {
+    fn from_env() -> Self {
+        let seconds = |name: &str| env::var(name).ok().and_then(|v| v.parse().ok()).filter(|&v| v > 0);
+        SessionLifetime {
+            idle_timeout: seconds("SESSION_IDLE_TIMEOUT"),
+            // Matches Cognito's default refresh token validity
+            max_age: seconds("SESSION_MAX_AGE").unwrap_or(30 * 24 * 60 * 60),
+            remember_me_max_age: seconds("SESSION_REMEMBER_ME_MAX_AGE"),
+        }
+    }
+
+    /// The absolute lifetime of a session started with or without "remember me".
+    pub fn max_age(&self, remember: bool) -> u64 {
+        match self.remember_me_max_age {
+            Some(max_age) if remember => max_age,
+            _ => self.max_age,
+        }
+    }
+}
+
+/// The `SameSite` attribute of the session cookie.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum SameSiteMode {
+    Strict,
+    Lax,
+    None,
+}
+
+impl From<SameSiteMode> for SameSite {
+    fn from(mode: SameSiteMode) -> Self {
+        match mode {
+            SameSiteMode::Strict => SameSite::Strict,
+            SameSiteMode::Lax => SameSite::Lax,
+            SameSiteMode::None => SameSite::None,
+        }
+    }
+}
+
+/// A cookie name prefix browsers enforce extra rules for.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum CookiePrefix {
+    /// `__Host-`: Secure, `Path=/` and no `Domain`, pinning the cookie to one host
+    Host,
+    /// `__Secure-`: only ever set over HTTPS
+    Secure,
+}
+
+/// Attributes of the session cookie.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct SessionCookieConfig {
+    /// Name without the prefix
+    pub name: String,
+    /// Share the cookie with subdomains of this domain instead of only this host
+    pub domain: Option<String>,
+    pub path: String,
+    pub same_site: SameSiteMode,
+    pub prefix: Option<CookiePrefix>,
+}
+
+impl Default for SessionCookieConfig {
+    fn default() -> Self {
+        SessionCookieConfig {
+            name: "authy_session".to_string(),
+            domain: None,
+            path: "/".to_string(),
+            same_site: SameSiteMode::Lax,
+            prefix: None,
+        }
+    }
+}
+
+impl SessionCookieConfig {
+    fn from_env(errors: &mut Vec<String>) -> Self {
+        let default = Self::default();
+        let same_site = match env::var("SESSION_COOKIE_SAMESITE").unwrap_or_default().to_lowercase().as_str() {
+            "" | "lax" => SameSiteMode::Lax,
+            "strict" => SameSiteMode::Strict,
+            "none" => SameSiteMode::None,
+            other => {
+                errors.push(format!("Unknown SESSION_COOKIE_SAMESITE {:?}, expected strict, lax or none", other));
+                SameSiteMode::Lax
+            }
+        };
+        let prefix = match env::var("SESSION_COOKIE_PREFIX").unwrap_or_default().to_lowercase().as_str() {
+            "" | "none" => None,
+            "host" | "__host-" => Some(CookiePrefix::Host),
+            "secure" | "__secure-" => Some(CookiePrefix::Secure),
+            other => {
+                errors.push(format!("Unknown SESSION_COOKIE_PREFIX {:?}, expected host, secure or none", other));
+                None
+            }
+        };
+
+        SessionCookieConfig {
+            name: env::var("SESSION_COOKIE_NAME").unwrap_or(default.name),
+            domain: env::var("SESSION_COOKIE_DOMAIN").ok().filter(|domain| !domain.is_empty()),
+            path: env::var("SESSION_COOKIE_PATH").unwrap_or(default.path),
+            same_site,
+            prefix,
+        }
+    }
+
+    /// The name as sent to browsers, prefix included.
+    pub fn full_name(&self) -> String {
+        let prefix = match self.prefix {
+            Some(CookiePrefix::Host) => "__Host-",
+            Some(CookiePrefix::Secure) => "__Secure-",
+            None => "",
+        };
+        format!("{}{}", prefix, self.name)
+    }
+
+    /// Reject attribute combinations browsers would refuse or silently drop.
+    fn validate(&self, secure: bool) -> Result<(), String> {
+        let is_token_char = |c: char| c.is_ascii_graphic() && !"()<>@,;:\\\"/[]?={}".contains(c);
+        if self.name.is_empty() || !self.name.chars().all(is_token_char) {
+            return Err(format!("SESSION_COOKIE_NAME {:?} is not a valid cookie name", self.name));
+        }
+        if self.name.starts_with("__Host-") || self.name.starts_with("__Secure-") {
+            return Err("Set SESSION_COOKIE_PREFIX instead of putting the prefix in SESSION_COOKIE_NAME".into());
+        }
+        if !self.path.starts_with('/') {
+            return Err(format!("SESSION_COOKIE_PATH {:?} must start with /", self.path));
+        }
+        if self.same_site == SameSiteMode::None && !secure {
+            return Err("SameSite=None cookies must be Secure, which needs an https SERVER_DOMAIN".into());
+        }
+        match self.prefix {
+            Some(_) if !secure => Err("Prefixed cookies must be Secure, which needs an https SERVER_DOMAIN".into()),
+            Some(CookiePrefix::Host) if self.domain.is_some() => {
+                Err("__Host- cookies cannot have a Domain, unset SESSION_COOKIE_DOMAIN".into())
+            }
+            Some(CookiePrefix::Host) if self.path != "/" => Err("__Host- cookies must use Path=/".into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// How authy sits in front of the protected website.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+pub enum GatewayMode {
+    /// Proxy signed-in requests to the website
+    #[default]
+    Proxy,
+    /// Only answer another reverse proxy's `/auth/verify` requests
+    ForwardAuth,
+}
+
+impl GatewayMode {
+    fn from_env(errors: &mut Vec<String>) -> Self {
+        match env::var("GATEWAY_MODE").unwrap_or_default().to_lowercase().as_str() {
+            "" | "proxy" => GatewayMode::Proxy,
+            "forward-auth" | "forward_auth" => GatewayMode::ForwardAuth,
+            other => {
+                errors.push(format!("Unknown GATEWAY_MODE {:?}, expected proxy or forward-auth", other));
+                GatewayMode::Proxy
+            }
+        }
+    }
+}
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Config {
-    pub cognito_domain: String,
-    pub cognito_client_id: String,
-    pub cognito_client_secret: String,
+    pub providers: Vec<ProviderConfig>,
     pub server_domain: String,
     pub protected_website_url: String,
+    pub mode: GatewayMode,
     pub port: u16,
     pub cors_allowed_origins: Vec<String>,
+    /// Leave CORS to the protected website, passing preflights through
+    pub cors_passthrough: bool,
     pub behind_proxy: bool,
+    pub pkce_enabled: bool,
+    pub cookie_secret: String,
+    pub logout_uri: String,
+    pub jwks_refresh_interval: u64,
+    /// Check `x5c` certificate chains published with signing keys
+    pub jwks_validate_x5c: bool,
+    /// PEM bundle of the CAs `x5c` chains must end at
+    pub jwks_x5c_ca_file: Option<String>,
+    /// Seconds of clock skew with providers tolerated on token times
+    pub token_leeway: u64,
+    /// PEM private key signing identity assertions for the protected website
+    pub assertion_key_file: Option<String>,
+    /// `aud` of assertions, the protected website's URL when unset
+    pub assertion_audience: Option<String>,
+    /// Seconds assertions stay valid
+    pub assertion_lifetime: u64,
+    /// Send the user's access token to the protected website as a bearer token
+    pub relay_access_token: bool,
+    /// Send the user's ID token to the protected website in `X-Authy-Id-Token`
+    pub relay_id_token: bool,
+    pub session_store: SessionStoreKind,
+    pub session_lifetime: SessionLifetime,
+    pub session_cookie: SessionCookieConfig,
+    pub access_policy: AccessPolicy,
+    #[serde(skip)]
+    pub routes: RouteTable,
+    #[serde(skip)]
+    pub identity_headers: IdentityHeaders,
+    /// Problems reading the other settings, reported by `validate`
+    #[serde(skip)]
+    pub errors: Vec<String>,
 }
 
 impl Config {
     pub fn from_env() -> Result<Self, env::VarError> {
+        let server_domain = env::var("SERVER_DOMAIN")?;
         let cors_origins = env::var("CORS_ALLOWED_ORIGINS")
             .unwrap_or_else(|_| String::from("*"))
             .split(',')
             .map(|s| s.trim().to_string())
             .collect();
+        let mut errors = Vec::new();
 
         Ok(Config {
-            cognito_domain: env::var("COGNITO_DOMAIN")?,
-            cognito_client_id: env::var("COGNITO_CLIENT_ID")?,
-            cognito_client_secret: env::var("COGNITO_CLIENT_SECRET")?,
-            server_domain: env::var("SERVER_DOMAIN")?,
+            providers: ProviderConfig::all_from_env()?,
+            logout_uri: env::var("LOGOUT_URI")
+                .unwrap_or_else(|_| format!("{}/", server_domain.trim_end_matches('/'))),
+            server_domain,
+            jwks_refresh_interval: env::var("JWKS_REFRESH_INTERVAL")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(3600),
+            jwks_validate_x5c: env::var("JWKS_VALIDATE_X5C")
+                .map(|v| v.to_lowercase() == "true")
+                .unwrap_or(false),
+            jwks_x5c_ca_file: env::var("JWKS_X5C_CA_FILE").ok().filter(|path| !path.is_empty()),
+            token_leeway: env::var("TOKEN_LEEWAY")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(60),
+            assertion_key_file: env::var("ASSERTION_KEY_FILE").ok().filter(|path| !path.is_empty()),
+            assertion_audience: env::var("ASSERTION_AUDIENCE").ok().filter(|aud| !aud.is_empty()),
+            assertion_lifetime: env::var("ASSERTION_LIFETIME")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(60),
+            relay_access_token: env::var("RELAY_ACCESS_TOKEN")
+                .map(|v| v.to_lowercase() == "true")
+                .unwrap_or(false),
+            relay_id_token: env::var("RELAY_ID_TOKEN")
+                .map(|v| v.to_lowercase() == "true")
+                .unwrap_or(false),
+            session_store: SessionStoreKind::from_env(&mut errors),
+            session_lifetime: SessionLifetime::from_env(),
+            session_cookie: SessionCookieConfig::from_env(&mut errors),
+            access_policy: AccessPolicy::from_env(),
+            routes: RouteTable::from_env(),
+            identity_headers: IdentityHeaders::from_env(),
             protected_website_url: env::var("PROTECTED_WEBSITE_URL")?,
+            mode: GatewayMode::from_env(&mut errors),
             port: env::var("PORT")?.parse().unwrap_or(3000),
             cors_allowed_origins: cors_origins,
+            cors_passthrough: env::var("CORS_PASSTHROUGH")
+                .map(|v| v.to_lowercase() == "true")
+                .unwrap_or(false),
             behind_proxy: env::var("BEHIND_PROXY")
                 .map(|v| v.to_lowercase() == "true")
                 .unwrap_or(false),
+            pkce_enabled: env::var("PKCE_ENABLED")
+                .map(|v| v.to_lowercase() != "false")
+                .unwrap_or(true),
+            cookie_secret: env::var("COOKIE_SECRET").unwrap_or_else(|_| {
+                tracing::warn!("COOKIE_SECRET not set, generating a random one; signed cookies will not survive a restart");
+                rand::thread_rng()
+                    .sample_iter(&Alphanumeric)
+                    .take(64)
+                    .map(char::from)
+                    .collect()
+            }),
+            errors,
         })
     }
+
+    /// Check settings that only make sense together.
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(error) = self.errors.first() {
+            return Err(error.clone());
+        }
+        if self.jwks_validate_x5c && self.jwks_x5c_ca_file.is_none() {
+            return Err("JWKS_VALIDATE_X5C needs the trusted CAs in JWKS_X5C_CA_FILE".into());
+        }
+        self.session_cookie.validate(self.is_https())?;
+        self.access_policy.validate()?;
+        self.routes.validate()?;
+        self.identity_headers.validate()
+    }
+
+    /// Whether authy is served over HTTPS, so its cookies can be Secure.
+    pub fn is_https(&self) -> bool {
+        self.server_domain.starts_with("https://")
+    }
+
+    /// Key used to sign and encrypt the short-lived cookies authy sets itself.
+    pub fn cookie_key(&self) -> Key {
+        Key::from(Sha512::digest(self.cookie_secret.as_bytes()).as_slice())
+    }
+}
+
+#[cfg(test)]
+impl ProviderConfig {
+    /// A Cognito pool whose hosted UI and token endpoints live at `domain`.
+    pub fn for_tests(domain: &str) -> Self {
+        ProviderConfig {
+            name: DEFAULT_PROVIDER.to_string(),
+            label: "Cognito".to_string(),
+            kind: ProviderKind::Cognito {
+                domain: domain.to_string(),
+                region: "us-east-1".to_string(),
+                user_pool_id: "us-east-1_test".to_string(),
+                identity_provider: None,
+            },
+            client_id: "test-client-id".to_string(),
+            client_secret: "test-client-secret".to_string(),
+            scopes: "openid".to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Config {
+    /// Baseline configuration for unit tests, override fields as needed.
+    pub fn for_tests() -> Self {
+        Config {
+            providers: vec![ProviderConfig::for_tests("https://test.auth.amazoncognito.com")],
+            server_domain: "http://localhost:3000".to_string(),
+            protected_website_url: "https://test-website.com".to_string(),
+            mode: GatewayMode::Proxy,
+            port: 3000,
+            cors_allowed_origins: vec!["*".to_string()],
+            cors_passthrough: false,
+            behind_proxy: false,
+            pkce_enabled: true,
+            cookie_secret: "test-cookie-secret".to_string(),
+            logout_uri: "http://localhost:3000/".to_string(),
+            jwks_refresh_interval: 3600,
+            jwks_validate_x5c: false,
+            jwks_x5c_ca_file: None,
+            token_leeway: 60,
+            assertion_key_file: None,
+            assertion_audience: None,
+            assertion_lifetime: 60,
+            relay_access_token: false,
+            relay_id_token: false,
+            session_store: SessionStoreKind::Memory,
+            session_lifetime: SessionLifetime {
+                idle_timeout: None,
+                max_age: 30 * 24 * 60 * 60,
+                remember_me_max_age: None,
+            },
+            session_cookie: SessionCookieConfig::default(),
+            access_policy: AccessPolicy::default(),
+            routes: RouteTable::default(),
+            identity_headers: IdentityHeaders::default(),
+            errors: Vec::new(),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -45,28 +583,222 @@ mod tests {
     fn test_config_from_env() {
         // Set up test environment variables
         env::set_var("COGNITO_DOMAIN", "https://test.auth.region.amazoncognito.com");
+        env::set_var("COGNITO_USER_POOL_ID", "eu-west-1_abcd1234");
         env::set_var("COGNITO_CLIENT_ID", "test-client-id");
         env::set_var("COGNITO_CLIENT_SECRET", "test-client-secret");
         env::set_var("SERVER_DOMAIN", "http://localhost:3000");
         env::set_var("PROTECTED_WEBSITE_URL", "https://test-website.com");
         env::set_var("PORT", "3000");
+        env::set_var("COOKIE_SECRET", "test-cookie-secret");
 
         // Test successful config creation
         let config = Config::from_env().unwrap();
-        assert_eq!(config.cognito_domain, "https://test.auth.region.amazoncognito.com");
-        assert_eq!(config.cognito_client_id, "test-client-id");
-        assert_eq!(config.cognito_client_secret, "test-client-secret");
+        assert_eq!(
+            config.providers[0].kind,
+            ProviderKind::Cognito {
+                domain: "https://test.auth.region.amazoncognito.com".to_string(),
+                region: "eu-west-1".to_string(),
+                user_pool_id: "eu-west-1_abcd1234".to_string(),
+                identity_provider: None,
+            }
+        );
+        assert_eq!(config.providers[0].kind.issuer(), "https://cognito-idp.eu-west-1.amazonaws.com/eu-west-1_abcd1234");
+        assert_eq!(config.providers[0].client_id, "test-client-id");
+        assert_eq!(config.providers[0].client_secret, "test-client-secret");
+        assert_eq!(config.providers[0].scopes, "openid");
+        assert_eq!(config.providers[0].name, "default");
+        assert_eq!(config.providers[0].callback_path(), "/callback");
         assert_eq!(config.server_domain, "http://localhost:3000");
         assert_eq!(config.protected_website_url, "https://test-website.com");
         assert_eq!(config.port, 3000);
+        assert!(config.pkce_enabled);
+        assert_eq!(config.cookie_secret, "test-cookie-secret");
+        assert_eq!(config.logout_uri, "http://localhost:3000/");
+        assert_eq!(config.jwks_refresh_interval, 3600);
+        assert_eq!(config.session_store, SessionStoreKind::Memory);
+        assert_eq!(config.session_lifetime, Config::for_tests().session_lifetime);
+
+        // Test session lifetimes
+        env::set_var("SESSION_IDLE_TIMEOUT", "1800");
+        env::set_var("SESSION_MAX_AGE", "43200");
+        env::set_var("SESSION_REMEMBER_ME_MAX_AGE", "1209600");
+        let lifetime = Config::from_env().unwrap().session_lifetime;
+        assert_eq!(lifetime.idle_timeout, Some(1800));
+        assert_eq!(lifetime.max_age(false), 43200);
+        assert_eq!(lifetime.max_age(true), 1209600);
+        env::remove_var("SESSION_IDLE_TIMEOUT");
+        env::remove_var("SESSION_MAX_AGE");
+        env::remove_var("SESSION_REMEMBER_ME_MAX_AGE");
+        assert_eq!(Config::from_env().unwrap().session_lifetime.max_age(true), 30 * 24 * 60 * 60);
+
+        // Test persistent session store
+        env::set_var("SESSION_STORE", "sqlite");
+        env::set_var("SESSION_DB_PATH", "/var/lib/authy/sessions.db");
+        assert_eq!(
+            Config::from_env().unwrap().session_store,
+            SessionStoreKind::Sqlite { path: "/var/lib/authy/sessions.db".to_string() }
+        );
+        env::remove_var("SESSION_DB_PATH");
+        env::set_var("SESSION_STORE", "sqllite");
+        let error = Config::from_env().unwrap().validate().unwrap_err();
+        assert!(error.contains("Unknown SESSION_STORE"), "{}", error);
+
+        // Test cookie sessions
+        env::set_var("SESSION_STORE", "cookie");
+        env::set_var("SESSION_KEYS", format!("new:{}, old:{}", STANDARD.encode([1; 32]), STANDARD.encode([2; 32])));
+        let config = Config::from_env().unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.session_store,
+            SessionStoreKind::Cookie {
+                keys: vec![
+                    SessionKey { id: "new".to_string(), key: [1; 32] },
+                    SessionKey { id: "old".to_string(), key: [2; 32] },
+                ],
+            }
+        );
+
+        // A malformed key fails start-up rather than promoting the next one
+        for (keys, error) in [
+            (format!("bad id:{}, old:{}", STANDARD.encode([1; 32]), STANDARD.encode([2; 32])), "entry 1: invalid key ID"),
+            (format!("short:AAAA, old:{}", STANDARD.encode([2; 32])), "entry 1: key is 3 bytes"),
+            (format!("new:{}, old", STANDARD.encode([1; 32])), "entry 2: expected <id>:"),
+            ("".to_string(), "SESSION_KEYS is empty"),
+        ] {
+            env::set_var("SESSION_KEYS", keys);
+            let result = Config::from_env().unwrap().validate();
+            assert!(matches!(&result, Err(message) if message.contains(error)), "{:?}", result);
+        }
+        env::remove_var("SESSION_STORE");
+        env::remove_var("SESSION_KEYS");
+
+        // Test session cookie attributes
+        env::set_var("SESSION_COOKIE_NAME", "app_session");
+        env::set_var("SESSION_COOKIE_DOMAIN", "example.com");
+        env::set_var("SESSION_COOKIE_PATH", "/app");
+        env::set_var("SESSION_COOKIE_SAMESITE", "Strict");
+        env::set_var("SESSION_COOKIE_PREFIX", "secure");
+        let cookie = Config::from_env().unwrap().session_cookie;
+        assert_eq!(cookie.full_name(), "__Secure-app_session");
+        assert_eq!(cookie.domain.as_deref(), Some("example.com"));
+        assert_eq!(cookie.path, "/app");
+        assert_eq!(cookie.same_site, SameSiteMode::Strict);
+        for name in ["SESSION_COOKIE_NAME", "SESSION_COOKIE_DOMAIN", "SESSION_COOKIE_PATH", "SESSION_COOKIE_SAMESITE", "SESSION_COOKIE_PREFIX"] {
+            env::remove_var(name);
+        }
+        assert_eq!(Config::from_env().unwrap().session_cookie, SessionCookieConfig::default());
+        for (name, value) in [("SESSION_COOKIE_SAMESITE", "strickt"), ("SESSION_COOKIE_PREFIX", "__host")] {
+            env::set_var(name, value);
+            let error = Config::from_env().unwrap().validate().unwrap_err();
+            assert!(error.starts_with(&format!("Unknown {}", name)), "{}", error);
+            env::remove_var(name);
+        }
+
+        // Test gateway modes
+        env::set_var("GATEWAY_MODE", "forward-auth");
+        assert_eq!(Config::from_env().unwrap().mode, GatewayMode::ForwardAuth);
+        env::set_var("GATEWAY_MODE", "forwardauth");
+        let error = Config::from_env().unwrap().validate().unwrap_err();
+        assert!(error.starts_with("Unknown GATEWAY_MODE"), "{}", error);
+        env::remove_var("GATEWAY_MODE");
+
+        // Test PKCE can be turned off
+        env::set_var("PKCE_ENABLED", "false");
+        assert!(!Config::from_env().unwrap().pkce_enabled);
+        env::remove_var("PKCE_ENABLED");
 
         // Test default port when PORT is not a valid number
         env::set_var("PORT", "invalid");
         let config = Config::from_env().unwrap();
         assert_eq!(config.port, 3000);
 
+        // Test generic OpenID Connect provider
+        env::set_var("OIDC_ISSUER", "https://keycloak.example.com/realms/main/");
+        env::set_var("OIDC_CLIENT_ID", "oidc-client");
+        env::set_var("OAUTH_SCOPES", "openid email");
+        let config = Config::from_env().unwrap();
+        assert_eq!(config.providers[0].kind.issuer(), "https://keycloak.example.com/realms/main");
+        assert_eq!(config.providers[0].client_id, "oidc-client");
+        assert_eq!(config.providers[0].client_secret, "");
+        assert_eq!(config.providers[0].scopes, "openid email");
+        env::remove_var("OIDC_ISSUER");
+        env::remove_var("OIDC_CLIENT_ID");
+        env::remove_var("OAUTH_SCOPES");
+
+        // Test several named providers
+        env::set_var("PROVIDERS", "corp, contractors");
+        env::set_var("CORP_OIDC_ISSUER", "https://login.example.com");
+        env::set_var("CORP_OIDC_CLIENT_ID", "corp-client");
+        env::set_var("CORP_LABEL", "Employees");
+        env::set_var("CONTRACTORS_COGNITO_DOMAIN", "https://contractors.auth.eu-west-1.amazoncognito.com");
+        env::set_var("CONTRACTORS_COGNITO_USER_POOL_ID", "eu-west-1_contract");
+        env::set_var("CONTRACTORS_COGNITO_CLIENT_ID", "contractors-client");
+        env::set_var("CONTRACTORS_COGNITO_IDENTITY_PROVIDER", "PartnerSAML");
+        let config = Config::from_env().unwrap();
+        let names: Vec<_> = config.providers.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["corp", "contractors"]);
+        assert_eq!(config.providers[0].label, "Employees");
+        assert_eq!(config.providers[0].callback_path(), "/callback/corp");
+        assert_eq!(config.providers[1].label, "contractors");
+        assert!(matches!(
+            &config.providers[1].kind,
+            ProviderKind::Cognito { identity_provider: Some(idp), .. } if idp == "PartnerSAML"
+        ));
+        for var in [
+            "PROVIDERS",
+            "CORP_OIDC_ISSUER",
+            "CORP_OIDC_CLIENT_ID",
+            "CORP_LABEL",
+            "CONTRACTORS_COGNITO_DOMAIN",
+            "CONTRACTORS_COGNITO_USER_POOL_ID",
+            "CONTRACTORS_COGNITO_CLIENT_ID",
+            "CONTRACTORS_COGNITO_IDENTITY_PROVIDER",
+        ] {
+            env::remove_var(var);
+        }
+
         // Test error when required variable is missing
         env::remove_var("COGNITO_DOMAIN");
         assert!(Config::from_env().is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_validate_session_cookie() {
+        let https = Config {
+            server_domain: "https://auth.example.com".to_string(),
+            ..Config::for_tests()
+        };
+        let with_cookie = |config: &Config, cookie: SessionCookieConfig| Config {
+            session_cookie: cookie,
+            ..config.clone()
+        };
+
+        assert!(Config::for_tests().validate().is_ok());
+        let x5c = Config { jwks_validate_x5c: true, ..Config::for_tests() };
+        assert!(x5c.validate().unwrap_err().contains("JWKS_X5C_CA_FILE"));
+        let x5c = Config { jwks_x5c_ca_file: Some("/etc/authy/ca.pem".to_string()), ..x5c };
+        assert!(x5c.validate().is_ok());
+        let host = SessionCookieConfig { prefix: Some(CookiePrefix::Host), ..Default::default() };
+        assert!(with_cookie(&https, host.clone()).validate().is_ok());
+        let shared = SessionCookieConfig {
+            domain: Some("example.com".to_string()),
+            same_site: SameSiteMode::None,
+            prefix: Some(CookiePrefix::Secure),
+            ..Default::default()
+        };
+        assert!(with_cookie(&https, shared.clone()).validate().is_ok());
+
+        for (config, cookie, error) in [
+            (&Config::for_tests(), shared.clone(), "SameSite=None"),
+            (&Config::for_tests(), host.clone(), "must be Secure"),
+            (&https, SessionCookieConfig { domain: Some("example.com".to_string()), ..host.clone() }, "cannot have a Domain"),
+            (&https, SessionCookieConfig { path: "/app".to_string(), ..host }, "Path=/"),
+            (&https, SessionCookieConfig { name: "bad name".to_string(), ..Default::default() }, "not a valid cookie name"),
+            (&https, SessionCookieConfig { name: "__Host-x".to_string(), ..Default::default() }, "SESSION_COOKIE_PREFIX"),
+            (&https, SessionCookieConfig { path: "app".to_string(), ..Default::default() }, "must start with /"),
+        ] {
+            let result = with_cookie(config, cookie).validate();
+            assert!(matches!(&result, Err(message) if message.contains(error)), "{:?}", result);
+        }
+    }
+}
@@ -1,7 +1,9 @@
 use axum::{
-    http::StatusCode,
+    http::{header, StatusCode},
     response::{IntoResponse, Response},
+    Json,
 };
+use serde_json::json;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -15,6 +17,24 @@ pub enum AppError {
         client_ip: String,
         path: String,
     },
+
+    /// A signed-in user the access policy does not let through.
+    #[error("Forbidden: {message}")]
+    Forbidden {
+        message: String,
+        user: String,
+        client_ip: String,
+        path: String,
+    },
+
+    /// An unauthenticated browser navigation, answered with a redirect to login.
+    #[error("Login required: {message}")]
+    LoginRequired {
+        message: String,
+        client_ip: String,
+        path: String,
+        login_url: String,
+    },
     
     #[error("Configuration error: {0}")]
     Config(#[from] std::env::VarError),
@@ -26,6 +46,19 @@ pub enum AppError {
     Internal(String),
 }
 
+impl AppError {
+    /// Turn an `Unauthorized` error into a redirect to the login flow when
+    /// the request came from a browser navigation.
+    pub fn or_login_redirect(self, login_url: Option<String>) -> Self {
+        match (self, login_url) {
+            (AppError::Unauthorized { message, client_ip, path }, Some(login_url)) => {
+                AppError::LoginRequired { message, client_ip, path, login_url }
+            }
+            (error, _) => error,
+        }
+    }
+}
+
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
         let (status, message) = match self {
@@ -38,7 +71,37 @@ impl IntoResponse for AppError {
                     path,
                     message
                 );
-                (StatusCode::UNAUTHORIZED, message)
+                return (
+                    StatusCode::UNAUTHORIZED,
+                    [(header::WWW_AUTHENTICATE, "Bearer realm=\"authy\"")],
+                    Json(json!({ "error": "unauthorized", "message": message })),
+                )
+                    .into_response();
+            },
+            AppError::Forbidden { message, user, client_ip, path } => {
+                tracing::warn!(
+                    target: "security_log",
+                    "Denied access for user={} from IP={} to path={}. Reason: {}",
+                    user,
+                    client_ip,
+                    path,
+                    message
+                );
+                return (
+                    StatusCode::FORBIDDEN,
+                    Json(json!({ "error": "forbidden", "message": message })),
+                )
+                    .into_response();
+            },
+            AppError::LoginRequired { message, client_ip, path, login_url } => {
+                tracing::info!(
+                    target: "security_log",
+                    "Redirecting unauthenticated request from IP={} to path={} to login. Reason: {}",
+                    client_ip,
+                    path,
+                    message
+                );
+                return (StatusCode::FOUND, [(header::LOCATION, login_url)]).into_response();
             },
             AppError::Config(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
             AppError::Request(e) => (StatusCode::BAD_GATEWAY, e.to_string()),
@@ -79,7 +142,59 @@ mod tests {
         };
         let response = error.into_response();
         assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
-        assert_eq!(get_response_body(response).await, "Invalid token");
+        assert_eq!(response.headers().get("www-authenticate").unwrap(), "Bearer realm=\"authy\"");
+        assert_eq!(response.headers().get("content-type").unwrap(), "application/json");
+        assert_eq!(
+            get_response_body(response).await,
+            r#"{"error":"unauthorized","message":"Invalid token"}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_forbidden_error_response() {
+        let error = AppError::Forbidden {
+            message: "GET /admin requires one of the groups: admins".to_string(),
+            user: "user-1".to_string(),
+            client_ip: "192.168.1.1".to_string(),
+            path: "/admin".to_string(),
+        };
+        let response = error.into_response();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            get_response_body(response).await,
+            r#"{"error":"forbidden","message":"GET /admin requires one of the groups: admins"}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_login_required_response() {
+        let error = AppError::Unauthorized {
+            message: "No session cookie found".to_string(),
+            client_ip: "192.168.1.1".to_string(),
+            path: "/reports/42".to_string(),
+        }
+        .or_login_redirect(Some("http://localhost:3000/?return_to=%2Freports%2F42".to_string()));
+        let response = error.into_response();
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert_eq!(
+            response.headers().get("location").unwrap(),
+            "http://localhost:3000/?return_to=%2Freports%2F42"
+        );
+    }
+
+    #[test]
+    fn test_or_login_redirect_keeps_other_errors() {
+        let error = AppError::Internal("Server error".to_string())
+            .or_login_redirect(Some("/".to_string()));
+        assert!(matches!(error, AppError::Internal(_)));
+
+        let error = AppError::Unauthorized {
+            message: "Invalid token".to_string(),
+            client_ip: "192.168.1.1".to_string(),
+            path: "/api".to_string(),
+        }
+        .or_login_redirect(None);
+        assert!(matches!(error, AppError::Unauthorized { .. }));
     }
 
     #[tokio::test]
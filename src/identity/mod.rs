@@ -0,0 +1,163 @@
+use std::env;
+
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use serde_json::Value;
+
+use crate::session::Claims;
+
+/// Headers authy sets itself, never passed on from clients.
+const RESERVED_PREFIX: &str = "x-authy-";
+
+const DEFAULT_MAPPINGS: [(&str, &str); 3] = [
+    ("x-authy-user", "sub"),
+    ("x-authy-email", "email"),
+    ("x-authy-groups", "cognito:groups"),
+];
+
+/// Headers telling the protected website who the signed-in user is, each
+/// carrying one claim of their token.
+#[derive(Clone, Debug)]
+pub struct IdentityHeaders {
+    mappings: Vec<(HeaderName, String)>,
+    /// Problems reading the mappings, reported by `validate`
+    errors: Vec<String>,
+}
+
+impl Default for IdentityHeaders {
+    fn default() -> Self {
+        IdentityHeaders {
+            mappings: DEFAULT_MAPPINGS
+                .iter()
+                .map(|(header, claim)| (HeaderName::from_static(header), claim.to_string()))
+                .collect(),
+            errors: Vec::new(),
+        }
+    }
+}
+
+impl IdentityHeaders {
+    /// Read `IDENTITY_HEADERS`, `;` separated `<header>=<claim>` entries
+    /// replacing the default user, email and groups headers. Empty turns
+    /// identity headers off.
+    pub fn from_env() -> Self {
+        let Ok(mappings) = env::var("IDENTITY_HEADERS") else {
+            return IdentityHeaders::default();
+        };
+
+        let mut headers = IdentityHeaders { mappings: Vec::new(), errors: Vec::new() };
+        for mapping in mappings.split(';').map(str::trim).filter(|mapping| !mapping.is_empty()) {
+            if let Err(e) = headers.add(mapping) {
+                headers.errors.push(format!("IDENTITY_HEADERS entry {:?}: {}", mapping, e));
+            }
+        }
+        headers
+    }
+
+    /// Add a `<header>=<claim>` mapping.
+    pub fn add(&mut self, mapping: &str) -> Result<(), String> {
+        let (header, claim) = mapping.split_once('=').ok_or("expected <header>=<claim>")?;
+        let header = HeaderName::from_bytes(header.trim().as_bytes())
+            .map_err(|_| format!("invalid header name {:?}", header.trim()))?;
+        let claim = claim.trim();
+        if claim.is_empty() {
+            return Err("missing claim".into());
+        }
+        self.mappings.push((header, claim.to_string()));
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match self.errors.first() {
+            Some(error) => Err(error.clone()),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether a client-supplied header could pass for one of ours, and so
+    /// must not reach the website.
+    pub fn is_reserved(&self, name: &HeaderName) -> bool {
+        name.as_str().starts_with(RESERVED_PREFIX) || self.mappings.iter().any(|(header, _)| header == name)
+    }
+
+    /// The headers for a user with these claims.
+    ///
+    /// Lists are joined with commas. Claims that are missing, or that cannot
+    /// go in a header, are left out.
+    pub fn for_claims(&self, claims: &Claims) -> HeaderMap {
+        let claims = serde_json::to_value(claims).unwrap_or_default();
+        self.mappings
+            .iter()
+            .filter_map(|(header, claim)| {
+                let value = claim_text(&claims[claim.as_str()])?;
+                Some((header.clone(), HeaderValue::from_str(&value).ok()?))
+            })
+            .collect()
+    }
+}
+
+fn claim_text(value: &Value) -> Option<String> {
+    match value {
+        Value::String(text) => Some(text.clone()),
+        Value::Number(number) => Some(number.to_string()),
+        Value::Bool(flag) => Some(flag.to_string()),
+        Value::Array(values) if !values.is_empty() => {
+            values.iter().map(claim_text).collect::<Option<Vec<_>>>().map(|values| values.join(","))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims() -> Claims {
+        let mut claims = crate::store::tests::create_test_record(0).claims;
+        claims.email = Some("jdoe@example.com".to_string());
+        claims.groups = vec!["staff".to_string(), "editors".to_string()];
+        claims.extra.insert("custom:tenant".to_string(), "acme".into());
+        claims.extra.insert("custom:level".to_string(), 3.into());
+        claims.extra.insert("address".to_string(), serde_json::json!({ "country": "NZ" }));
+        claims
+    }
+
+    #[test]
+    fn test_for_claims() {
+        let headers = IdentityHeaders::default().for_claims(&claims());
+        assert_eq!(headers["x-authy-user"], "user-1");
+        assert_eq!(headers["x-authy-email"], "jdoe@example.com");
+        assert_eq!(headers["x-authy-groups"], "staff,editors");
+
+        let mut custom = IdentityHeaders { mappings: Vec::new(), errors: Vec::new() };
+        for mapping in ["X-Tenant = custom:tenant", "X-Level=custom:level", "X-Address=address", "X-Missing=nickname"] {
+            custom.add(mapping).unwrap();
+        }
+        let headers = custom.for_claims(&claims());
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers["x-tenant"], "acme");
+        assert_eq!(headers["x-level"], "3");
+
+        // Nothing that would let a claim inject another header
+        let mut claims = claims();
+        claims.email = Some("jdoe@example.com\r\nX-Authy-User: admin".to_string());
+        assert!(IdentityHeaders::default().for_claims(&claims).get("x-authy-email").is_none());
+    }
+
+    #[test]
+    fn test_add_invalid() {
+        let mut headers = IdentityHeaders::default();
+        for bad in ["X-Tenant", "X Tenant=custom:tenant", "X-Tenant="] {
+            assert!(headers.add(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_is_reserved() {
+        let mut headers = IdentityHeaders::default();
+        headers.add("Remote-User=username").unwrap();
+        for reserved in ["x-authy-user", "x-authy-anything", "remote-user"] {
+            assert!(headers.is_reserved(&HeaderName::from_static(reserved)), "{}", reserved);
+        }
+        assert!(!headers.is_reserved(&HeaderName::from_static("x-request-id")));
+    }
+}
@@ -0,0 +1,236 @@
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use serde_json::{json, Value};
+use x509_parser::{
+    oid_registry::{OID_EC_P256, OID_NIST_EC_P384, OID_SIG_ED25519},
+    prelude::*,
+    public_key::PublicKey,
+};
+
+/// CA certificates `x5c` chains must end at.
+pub struct TrustAnchors {
+    certs: Vec<Vec<u8>>,
+}
+
+impl TrustAnchors {
+    /// Read a PEM bundle of one or more certificates.
+    pub fn from_pem(bundle: &str) -> Result<Self, String> {
+        let certs: Vec<_> = ::pem::parse_many(bundle)
+            .map_err(|e| format!("Invalid PEM: {}", e))?
+            .into_iter()
+            .filter(|block| block.tag() == "CERTIFICATE")
+            .map(|block| block.into_contents())
+            .collect();
+        if certs.is_empty() {
+            return Err("No certificates found".into());
+        }
+        for der in &certs {
+            parse_x509_certificate(der).map_err(|e| format!("Invalid certificate: {}", e))?;
+        }
+        Ok(TrustAnchors { certs })
+    }
+
+    /// Whether `cert` is one of the anchors or is signed by one.
+    fn trusts(&self, cert: &X509Certificate, der: &[u8]) -> bool {
+        self.certs.iter().any(|anchor_der| {
+            if anchor_der.as_slice() == der {
+                return true;
+            }
+            let Ok((_, anchor)) = parse_x509_certificate(anchor_der) else {
+                return false;
+            };
+            anchor.validity().is_valid()
+                && cert.issuer() == anchor.subject()
+                && cert.verify_signature(Some(anchor.public_key())).is_ok()
+        })
+    }
+}
+
+/// Complete a JWK from the leaf certificate of its `x5c` chain.
+///
+/// Providers may publish only the chain, so missing key parameters are taken
+/// from the leaf. With `anchors` set the chain must also be in date, each
+/// certificate signed by the next, the last one trusted by the anchors, and
+/// the leaf must agree with any parameters the JWK already carries.
+pub fn apply(key: &mut Value, anchors: Option<&TrustAnchors>) -> Result<(), String> {
+    let Some(chain) = key.get("x5c").and_then(Value::as_array) else {
+        return Ok(());
+    };
+
+    let ders = chain
+        .iter()
+        .map(|cert| cert.as_str().and_then(|cert| STANDARD.decode(cert).ok()))
+        .collect::<Option<Vec<_>>>()
+        .ok_or("Invalid x5c certificate encoding")?;
+    let certs = ders
+        .iter()
+        .map(|der| parse_x509_certificate(der).map(|(_, cert)| cert))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Invalid x5c certificate: {}", e))?;
+    let leaf = certs.first().ok_or("Empty x5c chain")?;
+
+    if let Some(anchors) = anchors {
+        validate_chain(&certs, &ders, anchors)?;
+    }
+
+    let object = key.as_object_mut().ok_or("JWK is not an object")?;
+    for (name, value) in key_params(leaf.public_key())? {
+        match object.get(name) {
+            None => {
+                object.insert(name.to_string(), value);
+            }
+            Some(existing) if anchors.is_some() && *existing != value => {
+                return Err(format!("x5c certificate does not match the key's {}", name));
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(())
+}
+
+fn validate_chain(certs: &[X509Certificate], ders: &[Vec<u8>], anchors: &TrustAnchors) -> Result<(), String> {
+    for (idx, cert) in certs.iter().enumerate() {
+        if !cert.validity().is_valid() {
+            return Err(format!("x5c certificate {} is expired or not yet valid", idx));
+        }
+
+        // The last certificate must be a trusted CA or be issued by one
+        let Some(issuer) = certs.get(idx + 1) else {
+            if !anchors.trusts(cert, &ders[idx]) {
+                return Err(format!("x5c certificate {} is not issued by a trusted CA", idx));
+            }
+            break;
+        };
+        if !issuer.is_ca() || cert.issuer() != issuer.subject() {
+            return Err(format!("x5c certificate {} is not issued by the next one", idx));
+        }
+        cert.verify_signature(Some(issuer.public_key()))
+            .map_err(|_| format!("x5c certificate {} is not signed by the next one", idx))?;
+    }
+    Ok(())
+}
+
+/// The JWK parameters describing a certificate's public key.
+fn key_params(spki: &SubjectPublicKeyInfo) -> Result<Vec<(&'static str, Value)>, String> {
+    let encode = |bytes: &[u8]| json!(URL_SAFE_NO_PAD.encode(bytes));
+
+    match spki.parsed() {
+        Ok(PublicKey::RSA(rsa)) => Ok(vec![
+            ("kty", json!("RSA")),
+            ("n", encode(trim_leading_zeros(rsa.modulus))),
+            ("e", encode(trim_leading_zeros(rsa.exponent))),
+        ]),
+        Ok(PublicKey::EC(point)) => {
+            let curve = spki.algorithm.parameters.as_ref().and_then(|params| params.as_oid().ok());
+            let (crv, size) = match curve {
+                Some(oid) if oid == OID_EC_P256 => ("P-256", 32),
+                Some(oid) if oid == OID_NIST_EC_P384 => ("P-384", 48),
+                _ => return Err("Unsupported x5c curve".into()),
+            };
+            // Uncompressed points only: 0x04 || x || y
+            let data = point.data();
+            if data.len() != 1 + 2 * size || data[0] != 4 {
+                return Err("Unsupported x5c EC point encoding".into());
+            }
+            Ok(vec![
+                ("kty", json!("EC")),
+                ("crv", json!(crv)),
+                ("x", encode(&data[1..=size])),
+                ("y", encode(&data[1 + size..])),
+            ])
+        }
+        Ok(PublicKey::Unknown(x)) if spki.algorithm.algorithm == OID_SIG_ED25519 => Ok(vec![
+            ("kty", json!("OKP")),
+            ("crv", json!("Ed25519")),
+            ("x", encode(x)),
+        ]),
+        _ => Err("Unsupported x5c public key".into()),
+    }
+}
+
+fn trim_leading_zeros(bytes: &[u8]) -> &[u8] {
+    let start = bytes.iter().position(|&b| b != 0).unwrap_or(bytes.len());
+    &bytes[start..]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cert(name: &str) -> Value {
+        let certs: Value = serde_json::from_str(include_str!("../verifier/test_certs.json")).unwrap();
+        certs[name].clone()
+    }
+
+    fn anchors(names: &[&str]) -> TrustAnchors {
+        let bundle: String = names
+            .iter()
+            .map(|name| ::pem::encode(&::pem::Pem::new("CERTIFICATE", STANDARD.decode(cert(name).as_str().unwrap()).unwrap())))
+            .collect();
+        TrustAnchors::from_pem(&bundle).unwrap()
+    }
+
+    #[test]
+    fn test_fills_in_key_from_leaf() {
+        let mut rsa = json!({ "kid": "rsa", "x5c": [cert("leaf"), cert("ca")] });
+        apply(&mut rsa, Some(&anchors(&["ca"]))).unwrap();
+        assert_eq!(rsa["kty"], "RSA");
+        assert_eq!(rsa["e"], "AQAB");
+
+        let mut ec = json!({ "kid": "ec", "x5c": [cert("ec")] });
+        apply(&mut ec, Some(&anchors(&["ec"]))).unwrap();
+        assert_eq!(ec["crv"], "P-256");
+        assert_eq!(URL_SAFE_NO_PAD.decode(ec["y"].as_str().unwrap()).unwrap().len(), 32);
+
+        let mut ed = json!({ "kid": "ed", "x5c": [cert("ed")] });
+        apply(&mut ed, None).unwrap();
+        assert_eq!(ed["kty"], "OKP");
+
+        // Keys without a chain are left alone
+        let mut plain = json!({ "kid": "plain", "kty": "RSA", "n": "AQAB", "e": "AQAB" });
+        apply(&mut plain, Some(&anchors(&["ca"]))).unwrap();
+        assert_eq!(plain, json!({ "kid": "plain", "kty": "RSA", "n": "AQAB", "e": "AQAB" }));
+    }
+
+    #[test]
+    fn test_validates_chain_when_asked() {
+        let trusted = anchors(&["ca"]);
+
+        // The CA may be left out of the chain when the bundle has it
+        let mut leaf_only = json!({ "kid": "rsa", "x5c": [cert("leaf")] });
+        assert!(apply(&mut leaf_only, Some(&trusted)).is_ok());
+
+        // Signed by a different CA with the same name
+        let mut wrong_issuer = json!({ "kid": "rsa", "x5c": [cert("leaf"), cert("other_ca")] });
+        assert!(apply(&mut wrong_issuer.clone(), None).is_ok());
+        assert!(apply(&mut wrong_issuer, Some(&trusted)).unwrap_err().contains("not signed by the next one"));
+
+        // Self-consistent chains are not enough without a trusted CA
+        let mut untrusted_root = json!({ "kid": "rsa", "x5c": [cert("leaf"), cert("ca")] });
+        let error = apply(&mut untrusted_root, Some(&anchors(&["other_ca"]))).unwrap_err();
+        assert!(error.contains("certificate 1 is not issued by a trusted CA"), "{}", error);
+        let mut self_signed = json!({ "kid": "ec", "x5c": [cert("ec")] });
+        let error = apply(&mut self_signed, Some(&trusted)).unwrap_err();
+        assert!(error.contains("certificate 0 is not issued by a trusted CA"), "{}", error);
+
+        // Parameters that disagree with the certificate
+        let mut mismatch = json!({ "kid": "rsa", "kty": "RSA", "n": "AQAB", "e": "AQAB", "x5c": [cert("leaf")] });
+        assert!(apply(&mut mismatch.clone(), None).is_ok());
+        assert!(apply(&mut mismatch, Some(&trusted)).unwrap_err().contains("does not match the key's n"));
+
+        let mut garbage = json!({ "kid": "bad", "x5c": ["not base64!"] });
+        assert!(apply(&mut garbage, None).is_err());
+        let mut empty = json!({ "kid": "empty", "x5c": [] });
+        assert!(apply(&mut empty, None).is_err());
+    }
+
+    #[test]
+    fn test_trust_anchors_from_pem() {
+        assert!(TrustAnchors::from_pem("not a certificate").is_err());
+        assert!(TrustAnchors::from_pem(include_str!("../verifier/test_key.pem")).is_err());
+        let bad = ::pem::encode(&::pem::Pem::new("CERTIFICATE", vec![1, 2, 3]));
+        assert!(TrustAnchors::from_pem(&bad).is_err());
+    }
+}
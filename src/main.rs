@@ -1,19 +1,30 @@
+mod assertion;
 mod auth;
+mod clock;
 mod config;
 mod error;
+mod identity;
+mod jwks;
+#[cfg(any(test, feature = "mock-provider"))]
+mod mock;
+mod policy;
+mod provider;
 mod proxy;
 mod session;
 mod middleware;
+mod state;
+mod store;
+mod verifier;
 
 use axum::{
-    routing::get,
+    routing::{any, get, post},
     Router,
     http::{Method, HeaderName, HeaderValue, StatusCode, header::{AUTHORIZATION, ACCEPT, CONTENT_TYPE}, Request},
     response::IntoResponse,
     extract::State,
     body::Body,
 };
-use crate::{config::Config, proxy::proxy_request};
+use crate::{config::{Config, GatewayMode}, proxy::proxy_request, state::AppState};
 use dotenv::dotenv;
 use std::{net::SocketAddr, time::Duration};
 use tower_http::cors::{Any, CorsLayer};
@@ -32,24 +43,77 @@ async fn main() {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
+    // `authy mock-provider` runs the mock identity provider for local development
+    #[cfg(feature = "mock-provider")]
+    if std::env::args().nth(1).as_deref() == Some("mock-provider") {
+        mock::serve_from_env().await;
+        return;
+    }
+
+    // `authy policy test` checks a sample request against the access policy
+    if std::env::args().nth(1).as_deref() == Some("policy") {
+        std::process::exit(policy::cli::run(std::env::args().skip(2).collect()));
+    }
+
     // Load configuration
     let config = Config::from_env().expect("Failed to load configuration");
+    if let Err(e) = config.validate() {
+        panic!("Invalid configuration: {}", e);
+    }
     let port = config.port;
 
-    // Configure CORS
-    let cors = build_cors_layer(&config);
+    // Fetch signing keys up front and keep them fresh
+    let state = AppState::new(config).await.expect("Failed to discover identity provider");
+    for provider in &state.providers {
+        if let Err(e) = provider.jwks.refresh().await {
+            tracing::error!("Failed to fetch JWKS for {} at startup, will retry: {}", provider.name(), e);
+        }
+        provider.jwks.spawn_refresh_task();
+    }
+    if let Some(store) = state.sessions.store() {
+        store::spawn_purge_task(store.clone(), state.clock.clone());
+    }
 
     // Build application
-    let app = Router::new()
+    let app = build_router(state);
+
+    // Start server
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    tracing::info!("Starting server on {}", addr);
+    
+    axum::serve(
+        tokio::net::TcpListener::bind(addr).await.unwrap(),
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
+}
+
+fn build_router(state: AppState) -> Router {
+    let router = Router::new()
         .route("/", get(auth::login))
         .route("/callback", get(auth::callback))
-        .route("/health", get(health_check))
-        .fallback(|State(config): State<Config>, req: Request<Body>| async move {
-            proxy_request(State(config), req).await
-        })
-        .layer(cors)
+        .route("/callback/:provider", get(auth::callback))
+        .route("/logout", post(auth::logout))
+        .route("/health", get(health_check));
+    // Without a signing key the path belongs to the website
+    let router = match state.assertions.clone() {
+        Some(signer) => router.route("/auth/jwks.json", get(move || assertion::jwks(signer.clone()))),
+        None => router,
+    };
+    // In forward auth mode another reverse proxy talks to the website
+    let router = match state.config.mode {
+        GatewayMode::Proxy => router.fallback(|State(state): State<AppState>, req: Request<Body>| async move {
+            proxy_request(State(state), req).await
+        }),
+        GatewayMode::ForwardAuth => router.route("/auth/verify", any(auth::forward::verify)),
+    };
+    // With CORS left to the protected website, preflights reach the proxy
+    let router = if state.config.cors_passthrough { router } else { router.layer(build_cors_layer(&state.config)) };
+    router
         .layer(axum::middleware::from_fn(middleware::access_log))
-        .with_state(config);
+        .with_state(state)
+}
 
 fn build_cors_layer(config: &Config) -> CorsLayer {
     if config.cors_allowed_origins.contains(&"*".to_string()) {
@@ -88,14 +152,188 @@ async fn health_check() -> impl IntoResponse {
     StatusCode::OK
 }
 
-    // Start server
-    let addr = SocketAddr::from(([0, 0, 0, 0], port));
-    tracing::info!("Starting server on {}", addr);
-    
-    axum::serve(
-        tokio::net::TcpListener::bind(addr).await.unwrap(),
-        app.into_make_service_with_connect_info::<SocketAddr>(),
-    )
-    .await
-    .unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{ProviderConfig, ProviderKind},
+        mock::{MockConfig, MockUser},
+        policy::routes::RouteClass,
+    };
+    use axum::http::HeaderMap;
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    async fn send(app: &Router, request: Request<Body>) -> axum::response::Response {
+        app.clone().oneshot(request).await.unwrap()
+    }
+
+    fn get_request(uri: &str, cookies: &str) -> Request<Body> {
+        Request::builder()
+            .uri(uri)
+            .header("cookie", cookies)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    /// `name=value` pairs of every cookie a response sets, for the next request.
+    fn cookies_set(headers: &HeaderMap) -> String {
+        headers.get_all("set-cookie").iter()
+            .map(|v| v.to_str().unwrap().split(';').next().unwrap().to_string())
+            .filter(|pair| !pair.ends_with('='))
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+
+    fn location(response: &axum::response::Response) -> url::Url {
+        url::Url::parse(response.headers()["location"].to_str().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_login_callback_and_proxied_request() {
+        let issuer = mock::spawn(
+            ([127, 0, 0, 1], 0).into(),
+            MockConfig {
+                client_secret: Some("mock-secret".to_string()),
+                user: MockUser { groups: vec!["admins".to_string()], ..MockUser::default() },
+                ..MockConfig::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        let website = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/dashboard"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("dashboard"))
+            .mount(&website)
+            .await;
+
+        // Real discovery and JWKS verification against the mock provider
+        let config = Config {
+            providers: vec![ProviderConfig {
+                kind: ProviderKind::Oidc { issuer: issuer.clone() },
+                client_id: "authy-mock".to_string(),
+                client_secret: "mock-secret".to_string(),
+                scopes: "openid email".to_string(),
+                ..ProviderConfig::for_tests("unused")
+            }],
+            protected_website_url: website.uri(),
+            cors_allowed_origins: vec!["http://localhost:3000".to_string()],
+            ..Config::for_tests()
+        };
+        let app = build_router(AppState::new(config).await.unwrap());
+
+        // Login sends the browser to the provider...
+        let response = send(&app, get_request("/?return_to=/dashboard", "")).await;
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        let login_cookies = cookies_set(response.headers());
+        let authorize_url = location(&response);
+        assert!(authorize_url.as_str().starts_with(&issuer));
+
+        // ...which signs the user in and comes back with a code...
+        let provider_response = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap()
+            .get(authorize_url)
+            .send()
+            .await
+            .unwrap();
+        let callback_url = url::Url::parse(provider_response.headers()["location"].to_str().unwrap()).unwrap();
+        assert_eq!(callback_url.path(), "/callback");
+
+        // ...that the callback exchanges for a session
+        let callback = format!("/callback?{}", callback_url.query().unwrap());
+        let response = send(&app, get_request(&callback, &login_cookies)).await;
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert_eq!(location(&response).as_str(), format!("{}/dashboard", website.uri()));
+        let session_cookies = cookies_set(response.headers());
+        assert!(session_cookies.starts_with("authy_session="));
+
+        // The session gets the user through to the protected website
+        let response = send(&app, get_request("/dashboard", &session_cookies)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"dashboard");
+
+        // Until they sign out
+        let logout = Request::builder()
+            .method(Method::POST)
+            .uri("/logout")
+            .header("cookie", &session_cookies)
+            .body(Body::empty())
+            .unwrap();
+        let response = send(&app, logout).await;
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert!(location(&response).as_str().starts_with(&format!("{}/logout", issuer)));
+        let response = send(&app, get_request("/dashboard", &session_cookies)).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_mode_leaves_auth_paths_to_the_website() {
+        let website = MockServer::start().await;
+        for route in ["/auth/verify", "/auth/jwks.json"] {
+            Mock::given(method("GET"))
+                .and(path(route))
+                .respond_with(ResponseTemplate::new(200).set_body_string("website"))
+                .mount(&website)
+                .await;
+        }
+        let mut config = Config {
+            protected_website_url: website.uri(),
+            cors_allowed_origins: vec!["http://localhost:3000".to_string()],
+            ..Config::for_tests()
+        };
+        config.routes.add(RouteClass::Public, "/auth/**").unwrap();
+        let mut state = AppState::for_tests(config).await;
+        let app = build_router(state.clone());
+
+        for route in ["/auth/verify", "/auth/jwks.json"] {
+            let response = send(&app, get_request(route, "")).await;
+            assert_eq!(response.status(), StatusCode::OK, "{}", route);
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(&body[..], b"website", "{}", route);
+        }
+
+        // Signing assertions claims the JWKS path
+        let signer = assertion::AssertionSigner::from_pem(
+            include_str!("verifier/test_key.pem"),
+            "http://localhost:3000".to_string(),
+            website.uri(),
+            60,
+        )
+        .unwrap();
+        state.assertions = Some(std::sync::Arc::new(signer));
+        let app = build_router(state);
+        let response = send(&app, get_request("/auth/jwks.json", "")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let jwks: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(jwks["keys"][0]["alg"], "RS256");
+    }
+
+    #[tokio::test]
+    async fn test_forward_auth_mode_has_no_proxy() {
+        let config = Config {
+            mode: GatewayMode::ForwardAuth,
+            cors_allowed_origins: vec!["http://localhost:3000".to_string()],
+            ..Config::for_tests()
+        };
+        let app = build_router(AppState::for_tests(config).await);
+
+        let response = send(&app, get_request("/dashboard", "")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let request = Request::builder()
+            .uri("/auth/verify")
+            .header("x-original-uri", "/dashboard")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(&app, request).await.status(), StatusCode::UNAUTHORIZED);
+    }
 }
@@ -0,0 +1,517 @@
+#[cfg(feature = "mock-provider")]
+use std::env;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Form, Json, Router,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use jsonwebtoken::{encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use url::Url;
+
+use crate::{
+    auth::pkce::{challenge_for, random_urlsafe},
+    clock::{Clock, SystemClock},
+};
+
+/// Key ID of the bundled signing key.
+const KEY_ID: &str = "authy-mock";
+
+/// The user every login signs in as.
+#[derive(Clone, Debug)]
+pub struct MockUser {
+    pub sub: String,
+    pub username: String,
+    pub email: String,
+    pub groups: Vec<String>,
+    /// Added to both the access and the ID token
+    pub extra_claims: Map<String, Value>,
+}
+
+impl Default for MockUser {
+    fn default() -> Self {
+        MockUser {
+            sub: "mock-user".to_string(),
+            username: "mock-user".to_string(),
+            email: "mock-user@example.com".to_string(),
+            groups: Vec::new(),
+            extra_claims: Map::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MockConfig {
+    /// Public base URL, derived from the bound address when unset
+    pub issuer: Option<String>,
+    pub client_id: String,
+    /// Required from clients when set, otherwise any secret or none is accepted
+    pub client_secret: Option<String>,
+    pub user: MockUser,
+    /// Seconds issued tokens are valid for
+    pub token_lifetime: u64,
+}
+
+impl Default for MockConfig {
+    fn default() -> Self {
+        MockConfig {
+            issuer: None,
+            client_id: "authy-mock".to_string(),
+            client_secret: None,
+            user: MockUser::default(),
+            token_lifetime: 3600,
+        }
+    }
+}
+
+#[cfg(feature = "mock-provider")]
+impl MockConfig {
+    pub fn from_env() -> Self {
+        let default = MockConfig::default();
+        let user = MockUser {
+            sub: env::var("MOCK_USER_SUB").unwrap_or(default.user.sub),
+            username: env::var("MOCK_USER_NAME").unwrap_or(default.user.username),
+            email: env::var("MOCK_USER_EMAIL").unwrap_or(default.user.email),
+            groups: env::var("MOCK_USER_GROUPS")
+                .map(|groups| {
+                    groups.split(',')
+                        .map(|group| group.trim().to_string())
+                        .filter(|group| !group.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            extra_claims: env::var("MOCK_USER_CLAIMS")
+                .ok()
+                .and_then(|claims| serde_json::from_str(&claims).ok())
+                .unwrap_or_default(),
+        };
+
+        MockConfig {
+            issuer: env::var("MOCK_ISSUER").ok(),
+            client_id: env::var("MOCK_CLIENT_ID").unwrap_or(default.client_id),
+            client_secret: env::var("MOCK_CLIENT_SECRET").ok(),
+            user,
+            token_lifetime: env::var("MOCK_TOKEN_LIFETIME")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default.token_lifetime),
+        }
+    }
+}
+
+/// A login waiting for its code to be exchanged.
+struct PendingCode {
+    redirect_uri: String,
+    code_challenge: Option<String>,
+    nonce: Option<String>,
+}
+
+struct MockState {
+    config: MockConfig,
+    issuer: String,
+    codes: Mutex<HashMap<String, PendingCode>>,
+    refresh_tokens: Mutex<HashMap<String, Option<String>>>,
+}
+
+/// Serve the mock provider on `addr` in the background, returning its issuer URL.
+#[cfg(test)]
+pub async fn spawn(addr: SocketAddr, config: MockConfig) -> std::io::Result<String> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    let issuer = config
+        .issuer
+        .clone()
+        .unwrap_or_else(|| format!("http://{}", listener.local_addr().unwrap()));
+    let app = router(config, issuer.clone());
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    Ok(issuer)
+}
+
+/// Run the mock provider in the foreground, configured from `MOCK_*` variables.
+#[cfg(feature = "mock-provider")]
+pub async fn serve_from_env() {
+    let port: u16 = env::var("MOCK_PORT").ok().and_then(|v| v.parse().ok()).unwrap_or(9000);
+    let config = MockConfig::from_env();
+    let issuer = config.issuer.clone().unwrap_or_else(|| format!("http://localhost:{}", port));
+    tracing::warn!("Starting the mock identity provider at {}, it signs in anyone and is not for production", issuer);
+
+    let listener = tokio::net::TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], port)))
+        .await
+        .expect("Failed to bind mock provider port");
+    axum::serve(listener, router(config, issuer)).await.unwrap();
+}
+
+/// Routes of a Cognito hosted UI look-alike that also answers OpenID discovery.
+pub fn router(config: MockConfig, issuer: String) -> Router {
+    let state = Arc::new(MockState {
+        config,
+        issuer: issuer.trim_end_matches('/').to_string(),
+        codes: Mutex::new(HashMap::new()),
+        refresh_tokens: Mutex::new(HashMap::new()),
+    });
+
+    Router::new()
+        .route("/.well-known/openid-configuration", get(discovery))
+        .route("/.well-known/jwks.json", get(jwks))
+        .route("/login", get(authorize))
+        .route("/oauth2/authorize", get(authorize))
+        .route("/oauth2/token", post(token))
+        .route("/oauth2/userInfo", get(user_info))
+        .route("/oauth2/revoke", post(revoke))
+        .route("/logout", get(logout))
+        .with_state(state)
+}
+
+async fn discovery(State(state): State<Arc<MockState>>) -> Json<Value> {
+    let issuer = &state.issuer;
+    Json(json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{}/oauth2/authorize", issuer),
+        "token_endpoint": format!("{}/oauth2/token", issuer),
+        "userinfo_endpoint": format!("{}/oauth2/userInfo", issuer),
+        "end_session_endpoint": format!("{}/logout", issuer),
+        "revocation_endpoint": format!("{}/oauth2/revoke", issuer),
+        "jwks_uri": format!("{}/.well-known/jwks.json", issuer),
+        "response_types_supported": ["code"],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": ["RS256"],
+        "code_challenge_methods_supported": ["S256"],
+    }))
+}
+
+async fn jwks() -> Response {
+    ([(header::CONTENT_TYPE, "application/json")], include_str!("mock_jwks.json")).into_response()
+}
+
+#[derive(Deserialize)]
+struct AuthorizeParams {
+    client_id: Option<String>,
+    response_type: Option<String>,
+    redirect_uri: Option<String>,
+    state: Option<String>,
+    nonce: Option<String>,
+    code_challenge: Option<String>,
+    code_challenge_method: Option<String>,
+}
+
+/// Sign the configured user in straight away and send the code back.
+async fn authorize(State(state): State<Arc<MockState>>, Query(params): Query<AuthorizeParams>) -> Response {
+    if params.client_id.as_deref() != Some(state.config.client_id.as_str()) {
+        return (StatusCode::BAD_REQUEST, "unknown client_id").into_response();
+    }
+    if params.response_type.as_deref() != Some("code") {
+        return (StatusCode::BAD_REQUEST, "unsupported response_type").into_response();
+    }
+    if params.code_challenge.is_some() && params.code_challenge_method.as_deref() != Some("S256") {
+        return (StatusCode::BAD_REQUEST, "unsupported code_challenge_method").into_response();
+    }
+    let Some(mut redirect) = params.redirect_uri.as_deref().and_then(|uri| Url::parse(uri).ok()) else {
+        return (StatusCode::BAD_REQUEST, "invalid redirect_uri").into_response();
+    };
+
+    let code = random_urlsafe(16);
+    state.codes.lock().unwrap().insert(
+        code.clone(),
+        PendingCode {
+            redirect_uri: params.redirect_uri.clone().unwrap_or_default(),
+            code_challenge: params.code_challenge,
+            nonce: params.nonce,
+        },
+    );
+
+    redirect.query_pairs_mut().append_pair("code", &code);
+    if let Some(oauth_state) = &params.state {
+        redirect.query_pairs_mut().append_pair("state", oauth_state);
+    }
+    Redirect::to(redirect.as_str()).into_response()
+}
+
+#[derive(Deserialize)]
+struct TokenParams {
+    grant_type: String,
+    client_id: Option<String>,
+    code: Option<String>,
+    redirect_uri: Option<String>,
+    code_verifier: Option<String>,
+    refresh_token: Option<String>,
+}
+
+async fn token(
+    State(state): State<Arc<MockState>>,
+    headers: HeaderMap,
+    Form(params): Form<TokenParams>,
+) -> Response {
+    if !client_authenticated(&state.config, &headers, params.client_id.as_deref()) {
+        return oauth_error(StatusCode::UNAUTHORIZED, "invalid_client");
+    }
+
+    match params.grant_type.as_str() {
+        "authorization_code" => {
+            let pending = params.code.as_deref().and_then(|code| state.codes.lock().unwrap().remove(code));
+            let Some(pending) = pending else {
+                return oauth_error(StatusCode::BAD_REQUEST, "invalid_grant");
+            };
+            if params.redirect_uri.as_deref() != Some(pending.redirect_uri.as_str()) {
+                return oauth_error(StatusCode::BAD_REQUEST, "invalid_grant");
+            }
+            if let Some(challenge) = &pending.code_challenge {
+                let verified = params.code_verifier.as_deref().is_some_and(|verifier| challenge_for(verifier) == *challenge);
+                if !verified {
+                    return oauth_error(StatusCode::BAD_REQUEST, "invalid_grant");
+                }
+            }
+
+            let refresh_token = random_urlsafe(32);
+            state.refresh_tokens.lock().unwrap().insert(refresh_token.clone(), pending.nonce.clone());
+            let mut tokens = issue_tokens(&state, pending.nonce.as_deref());
+            tokens["refresh_token"] = json!(refresh_token);
+            Json(tokens).into_response()
+        }
+        "refresh_token" => {
+            let nonce = params.refresh_token.as_deref().and_then(|token| state.refresh_tokens.lock().unwrap().get(token).cloned());
+            match nonce {
+                // Like Cognito, the refresh token itself is not rotated
+                Some(nonce) => Json(issue_tokens(&state, nonce.as_deref())).into_response(),
+                None => oauth_error(StatusCode::BAD_REQUEST, "invalid_grant"),
+            }
+        }
+        _ => oauth_error(StatusCode::BAD_REQUEST, "unsupported_grant_type"),
+    }
+}
+
+/// Accept HTTP basic credentials or a bare `client_id` for public clients.
+fn client_authenticated(config: &MockConfig, headers: &HeaderMap, form_client_id: Option<&str>) -> bool {
+    let basic = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Basic "))
+        .and_then(|v| STANDARD.decode(v).ok())
+        .and_then(|v| String::from_utf8(v).ok());
+
+    let (client_id, secret) = match &basic {
+        Some(credentials) => match credentials.split_once(':') {
+            Some((id, secret)) => (Some(id), Some(secret)),
+            None => (None, None),
+        },
+        None => (form_client_id, None),
+    };
+
+    let secret_ok = match &config.client_secret {
+        Some(expected) => secret == Some(expected.as_str()),
+        None => true,
+    };
+    client_id == Some(config.client_id.as_str()) && secret_ok
+}
+
+fn issue_tokens(state: &MockState, nonce: Option<&str>) -> Value {
+    let user = &state.config.user;
+    let issued_at = SystemClock.now();
+    let expires_at = issued_at + state.config.token_lifetime;
+
+    let mut access = json!({
+        "sub": user.sub,
+        "iss": state.issuer,
+        "client_id": state.config.client_id,
+        "token_use": "access",
+        "scope": "openid email profile",
+        "username": user.username,
+        "cognito:groups": user.groups,
+        "iat": issued_at,
+        "exp": expires_at,
+    });
+    let mut id = json!({
+        "sub": user.sub,
+        "iss": state.issuer,
+        "aud": state.config.client_id,
+        "token_use": "id",
+        "cognito:username": user.username,
+        "email": user.email,
+        "email_verified": true,
+        "cognito:groups": user.groups,
+        "iat": issued_at,
+        "exp": expires_at,
+    });
+    if let Some(nonce) = nonce {
+        id["nonce"] = json!(nonce);
+    }
+    for (name, value) in &user.extra_claims {
+        access[name] = value.clone();
+        id[name] = value.clone();
+    }
+
+    json!({
+        "access_token": sign(&access),
+        "id_token": sign(&id),
+        "token_type": "Bearer",
+        "expires_in": state.config.token_lifetime,
+    })
+}
+
+/// Sign claims with the bundled mock key.
+pub fn sign(claims: &Value) -> String {
+    let header = Header {
+        kid: Some(KEY_ID.to_string()),
+        ..Header::new(Algorithm::RS256)
+    };
+    let key = EncodingKey::from_rsa_pem(include_bytes!("mock_key.pem")).expect("bundled mock key is valid");
+    encode(&header, claims, &key).expect("claims are always serializable")
+}
+
+fn decoding_key() -> DecodingKey {
+    let jwks: jsonwebtoken::jwk::JwkSet =
+        serde_json::from_str(include_str!("mock_jwks.json")).expect("bundled mock JWKS is valid");
+    DecodingKey::from_jwk(&jwks.keys[0]).expect("bundled mock JWKS is valid")
+}
+
+async fn user_info(State(state): State<Arc<MockState>>, headers: HeaderMap) -> Response {
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    let Some(token) = token else {
+        return oauth_error(StatusCode::UNAUTHORIZED, "invalid_token");
+    };
+
+    let mut validation = Validation::new(Algorithm::RS256);
+    validation.set_issuer(&[&state.issuer]);
+    validation.validate_aud = false;
+    if jsonwebtoken::decode::<Value>(token, &decoding_key(), &validation).is_err() {
+        return oauth_error(StatusCode::UNAUTHORIZED, "invalid_token");
+    }
+
+    let user = &state.config.user;
+    let mut info = json!({
+        "sub": user.sub,
+        "username": user.username,
+        "email": user.email,
+        "email_verified": "true",
+        "cognito:groups": user.groups,
+    });
+    for (name, value) in &user.extra_claims {
+        info[name] = value.clone();
+    }
+    Json(info).into_response()
+}
+
+#[derive(Deserialize)]
+struct RevokeParams {
+    token: String,
+}
+
+async fn revoke(State(state): State<Arc<MockState>>, Form(params): Form<RevokeParams>) -> StatusCode {
+    state.refresh_tokens.lock().unwrap().remove(&params.token);
+    StatusCode::OK
+}
+
+#[derive(Deserialize)]
+struct LogoutParams {
+    logout_uri: Option<String>,
+    post_logout_redirect_uri: Option<String>,
+}
+
+/// Both Cognito's `logout_uri` and OpenID's `post_logout_redirect_uri` work.
+async fn logout(Query(params): Query<LogoutParams>) -> Response {
+    match params.logout_uri.or(params.post_logout_redirect_uri) {
+        Some(target) => Redirect::to(&target).into_response(),
+        None => "Signed out".into_response(),
+    }
+}
+
+fn oauth_error(status: StatusCode, error: &str) -> Response {
+    (status, Json(json!({ "error": error }))).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run a login against the mock, returning the code sent back.
+    async fn authorize_code(issuer: &str, challenge: &str) -> String {
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        let response = client
+            .get(format!("{}/login", issuer))
+            .query(&[
+                ("client_id", "authy-mock"),
+                ("response_type", "code"),
+                ("redirect_uri", "http://localhost:3000/callback"),
+                ("state", "xyz"),
+                ("code_challenge", challenge),
+                ("code_challenge_method", "S256"),
+            ])
+            .send()
+            .await
+            .unwrap();
+        let location = Url::parse(response.headers()["location"].to_str().unwrap()).unwrap();
+        assert_eq!(location.path(), "/callback");
+        let query: HashMap<_, _> = location.query_pairs().into_owned().collect();
+        assert_eq!(query["state"], "xyz");
+        query["code"].clone()
+    }
+
+    async fn exchange(issuer: &str, secret: &str, code: &str, verifier: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/oauth2/token", issuer))
+            .basic_auth("authy-mock", Some(secret))
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", "http://localhost:3000/callback"),
+                ("code_verifier", verifier),
+            ])
+            .send()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_code_exchange() {
+        let config = MockConfig {
+            client_secret: Some("mock-secret".to_string()),
+            user: MockUser { groups: vec!["admins".to_string()], ..MockUser::default() },
+            ..MockConfig::default()
+        };
+        let issuer = spawn(([127, 0, 0, 1], 0).into(), config).await.unwrap();
+        let verifier = random_urlsafe(32);
+
+        // Wrong secret and wrong verifier are refused, the latter using up the code
+        let code = authorize_code(&issuer, &challenge_for(&verifier)).await;
+        assert_eq!(exchange(&issuer, "wrong", &code, &verifier).await.status(), 401);
+        assert_eq!(exchange(&issuer, "mock-secret", &code, "wrong").await.status(), 400);
+        assert_eq!(exchange(&issuer, "mock-secret", &code, &verifier).await.status(), 400);
+
+        let code = authorize_code(&issuer, &challenge_for(&verifier)).await;
+        let tokens: Value = exchange(&issuer, "mock-secret", &code, &verifier).await.json().await.unwrap();
+
+        // Signed with the key the JWKS publishes
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.set_audience(&["authy-mock"]);
+        let id_token = jsonwebtoken::decode::<Value>(tokens["id_token"].as_str().unwrap(), &decoding_key(), &validation)
+            .unwrap()
+            .claims;
+        assert_eq!(id_token["iss"], issuer);
+        assert_eq!(id_token["cognito:groups"], json!(["admins"]));
+
+        let info: Value = reqwest::Client::new()
+            .get(format!("{}/oauth2/userInfo", issuer))
+            .bearer_auth(tokens["access_token"].as_str().unwrap())
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(info["sub"], "mock-user");
+    }
+}
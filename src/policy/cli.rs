@@ -0,0 +1,126 @@
+use axum::http::Method;
+use serde_json::{json, Value};
+
+use super::{AccessPolicy, Decision};
+
+const USAGE: &str = "Usage: authy policy test [--file <policy.toml>] <METHOD> <PATH> [<claims JSON>]";
+
+/// `authy policy test`: evaluate a sample request against the access policy
+/// and print which rule decided it.
+///
+/// Exits with 0 when the request would be allowed, 1 when denied and 2 when
+/// the policy or the arguments are invalid.
+pub fn run(args: Vec<String>) -> i32 {
+    match explain(&args) {
+        Ok((Decision::Allow, explanation)) => {
+            println!("{}", explanation);
+            0
+        }
+        Ok((Decision::Deny, explanation)) => {
+            println!("{}", explanation);
+            1
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            2
+        }
+    }
+}
+
+fn explain(args: &[String]) -> Result<(Decision, String), String> {
+    let mut args = args.iter().map(String::as_str);
+    if args.next() != Some("test") {
+        return Err(USAGE.into());
+    }
+
+    let mut file = None;
+    let mut positional = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg {
+            "--file" | "-f" => file = Some(args.next().ok_or(USAGE)?),
+            _ => positional.push(arg),
+        }
+    }
+    let (method, path, claims) = match positional[..] {
+        [method, path] => (method, path, None),
+        [method, path, claims] => (method, path, Some(claims)),
+        _ => return Err(USAGE.into()),
+    };
+
+    let method = Method::from_bytes(method.to_uppercase().as_bytes())
+        .map_err(|_| format!("Invalid method {:?}", method))?;
+    let claims: Value = match claims {
+        Some(claims) => serde_json::from_str(claims).map_err(|e| format!("Invalid claims JSON: {}", e))?,
+        None => json!({}),
+    };
+    let policy = match file {
+        Some(file) => AccessPolicy::from_file(file)?,
+        None => {
+            let policy = AccessPolicy::from_env();
+            policy.validate()?;
+            policy
+        }
+    };
+
+    let verdict = policy.evaluate(&method, path, &claims);
+    let explanation = match verdict.rule {
+        Some((idx, rule)) => {
+            let reason = match (&rule.reason, verdict.decision) {
+                (Some(reason), Decision::Deny) => format!(": {}", reason),
+                _ => String::new(),
+            };
+            format!("{} {} {} (rule {}{})", verdict.decision, method, path, rule.label(idx), reason)
+        }
+        None => format!("{} {} {} (no rule matched, default)", verdict.decision, method, path),
+    };
+    Ok((verdict.decision, explanation))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn explain_with(file: &str, args: &[&str]) -> Result<(Decision, String), String> {
+        let mut all = vec!["test".to_string(), "--file".to_string(), file.to_string()];
+        all.extend(args.iter().map(|arg| arg.to_string()));
+        explain(&all)
+    }
+
+    #[test]
+    fn test_explain() {
+        let file = std::env::temp_dir().join(format!("authy-policy-cli-{}.toml", std::process::id()));
+        std::fs::write(&file, r#"
+            default = "deny"
+
+            [[rules]]
+            name = "admins"
+            paths = ["/admin/**"]
+            groups = ["admins"]
+
+            [[rules]]
+            paths = ["/admin/**"]
+            effect = "deny"
+            reason = "Admins only"
+        "#).unwrap();
+        let file = file.to_str().unwrap();
+
+        assert_eq!(
+            explain_with(file, &["get", "/admin/users", r#"{"cognito:groups": ["admins"]}"#]),
+            Ok((Decision::Allow, r#"allow GET /admin/users (rule "admins")"#.to_string()))
+        );
+        assert_eq!(
+            explain_with(file, &["GET", "/admin/users"]),
+            Ok((Decision::Deny, "deny GET /admin/users (rule #2: Admins only)".to_string()))
+        );
+        assert_eq!(
+            explain_with(file, &["GET", "/reports"]),
+            Ok((Decision::Deny, "deny GET /reports (no rule matched, default)".to_string()))
+        );
+
+        assert_eq!(explain_with(file, &["GET"]), Err(USAGE.to_string()));
+        assert!(explain_with(file, &["GET", "/", "not json"]).unwrap_err().starts_with("Invalid claims JSON"));
+        assert!(explain_with("/nonexistent.toml", &["GET", "/"]).unwrap_err().starts_with("Failed to read"));
+        assert_eq!(explain(&["check".to_string()]), Err(USAGE.to_string()));
+        std::fs::remove_file(file).unwrap();
+    }
+}
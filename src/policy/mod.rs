@@ -0,0 +1,539 @@
+use std::{collections::BTreeMap, env, fmt, fs};
+
+use axum::http::Method;
+use globset::{GlobBuilder, GlobMatcher};
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use serde_json::Value;
+
+pub mod cli;
+pub mod routes;
+
+/// Group that stands for every signed-in user in `ACCESS_RULES`.
+const ANY_USER: &str = "*";
+
+/// Who may reach which paths of the protected website.
+///
+/// Rules are tried in order and the first one whose conditions all hold
+/// decides, allowing or denying the request. Requests no rule matches get
+/// the default decision, which lets them through unless configured otherwise.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessPolicy {
+    #[serde(default)]
+    pub default: Decision,
+    #[serde(default)]
+    pub rules: Vec<AccessRule>,
+    /// Problems loading the policy, reported by `validate`
+    #[serde(skip)]
+    errors: Vec<String>,
+}
+
+/// A policy rule. Every condition given must hold for it to match, and
+/// within a condition any of the listed values will do.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AccessRule {
+    pub name: Option<String>,
+    #[serde(default)]
+    pub effect: Decision,
+    /// Told to users the rule denies
+    pub reason: Option<String>,
+    /// Path globs, `*` matching within a segment and `**` across segments
+    #[serde(default)]
+    pub paths: Vec<PathPattern>,
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// Matched against the `sub`, `username` and `email` claims
+    #[serde(default)]
+    pub users: Vec<String>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// Domains of verified email addresses
+    #[serde(default)]
+    pub email_domains: Vec<String>,
+    /// Accepted values by claim name, `*` for any value and `{path.N}` for
+    /// the Nth segment of the request path
+    #[serde(default)]
+    pub claims: BTreeMap<String, ClaimValues>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Decision {
+    #[default]
+    Allow,
+    Deny,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum ClaimValues {
+    One(String),
+    Any(Vec<String>),
+}
+
+/// A compiled path glob.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "String")]
+pub struct PathPattern {
+    pattern: String,
+    matcher: GlobMatcher,
+}
+
+/// The outcome of evaluating a request, with the rule that decided it.
+pub struct Verdict<'a> {
+    pub decision: Decision,
+    /// Position and rule, `None` when the default applied
+    pub rule: Option<(usize, &'a AccessRule)>,
+}
+
+impl AccessPolicy {
+    /// Load `POLICY_FILE` or the `ACCESS_RULES` shorthand, with the
+    /// `ACCESS_DEFAULT` override.
+    ///
+    /// `ACCESS_RULES` holds `;` separated `<path> [METHOD,...]=<group>,...`
+    /// entries, e.g. `/admin=admins; /api POST,DELETE=editors; /api=*`. Each
+    /// lets the groups in under the path prefix and turns everyone else away.
+    pub fn from_env() -> Self {
+        let file = env::var("POLICY_FILE").ok();
+        let mut policy = match &file {
+            Some(path) => AccessPolicy::from_file(path).unwrap_or_else(|e| AccessPolicy {
+                errors: vec![e],
+                ..AccessPolicy::default()
+            }),
+            None => AccessPolicy::default(),
+        };
+
+        if let Ok(rules) = env::var("ACCESS_RULES") {
+            if file.is_some() {
+                policy.errors.push("Set either POLICY_FILE or ACCESS_RULES, not both".into());
+            }
+            for rule in rules.split(';').map(str::trim).filter(|rule| !rule.is_empty()) {
+                match parse_group_rule(rule) {
+                    Ok(rules) => policy.rules.extend(rules),
+                    Err(e) => policy.errors.push(format!("ACCESS_RULES entry {:?}: {}", rule, e)),
+                }
+            }
+        }
+
+        match env::var("ACCESS_DEFAULT").map(|v| v.to_lowercase()).as_deref() {
+            Ok("allow") => policy.default = Decision::Allow,
+            Ok("deny") => policy.default = Decision::Deny,
+            Ok(other) => policy.errors.push(format!("ACCESS_DEFAULT must be allow or deny, not {:?}", other)),
+            Err(_) => {}
+        }
+        policy
+    }
+
+    /// Read a TOML policy file.
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read policy file {}: {}", path, e))?;
+        let policy: AccessPolicy = toml::from_str(&text)
+            .map_err(|e| format!("Invalid policy file {}: {}", path, e))?;
+        for (idx, rule) in policy.rules.iter().enumerate() {
+            rule.validate()
+                .map_err(|e| format!("Policy file {}, rule {}: {}", path, rule.label(idx), e))?;
+        }
+        Ok(policy)
+    }
+
+    /// Refuse to start with a policy we could not read, rather than guess.
+    pub fn validate(&self) -> Result<(), String> {
+        match self.errors.first() {
+            Some(error) => Err(error.clone()),
+            None => Ok(()),
+        }
+    }
+
+    /// Find the rule deciding a request by a user with these token claims.
+    pub fn evaluate(&self, method: &Method, path: &str, claims: &Value) -> Verdict<'_> {
+        let path = normalize_path(path);
+        let segments: Vec<&str> = path.split('/').filter(|segment| !segment.is_empty()).collect();
+
+        match self.rules.iter().enumerate().find(|(_, rule)| rule.matches(method, &path, &segments, claims)) {
+            Some((idx, rule)) => Verdict { decision: rule.effect, rule: Some((idx, rule)) },
+            None => Verdict { decision: self.default, rule: None },
+        }
+    }
+
+    /// Whether the request may go through, giving the reason when not.
+    pub fn check(&self, method: &Method, path: &str, claims: &Value) -> Result<(), String> {
+        match self.evaluate(method, path, claims) {
+            Verdict { decision: Decision::Allow, .. } => Ok(()),
+            Verdict { rule: Some((idx, rule)), .. } => Err(rule.reason.clone()
+                .unwrap_or_else(|| format!("Denied by access rule {}", rule.label(idx)))),
+            Verdict { rule: None, .. } => Err(format!("No access rule allows {} {}", method, path)),
+        }
+    }
+}
+
+impl AccessRule {
+    /// The rule's name, or its position counting from 1.
+    pub fn label(&self, idx: usize) -> String {
+        match &self.name {
+            Some(name) => format!("{:?}", name),
+            None => format!("#{}", idx + 1),
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if let Some(method) = self.methods.iter().find(|m| Method::from_bytes(m.as_bytes()).is_err()) {
+            return Err(format!("invalid method {:?}", method));
+        }
+        for (claim, values) in &self.claims {
+            if let Some(value) = values.values().iter().find(|v| v.starts_with("{path.") && path_index(v).is_none()) {
+                return Err(format!("claim {:?}: invalid placeholder {:?}, expected {{path.N}} with N from 1", claim, value));
+            }
+        }
+        Ok(())
+    }
+
+    fn matches(&self, method: &Method, path: &str, segments: &[&str], claims: &Value) -> bool {
+        (self.paths.is_empty() || self.paths.iter().any(|pattern| pattern.matcher.is_match(path)))
+            && (self.methods.is_empty() || self.methods.iter().any(|m| m.eq_ignore_ascii_case(method.as_str())))
+            && (self.users.is_empty() || ["sub", "username", "email"].iter().any(|claim| {
+                claims[claim].as_str().is_some_and(|user| self.users.iter().any(|u| u == user))
+            }))
+            && (self.groups.is_empty() || self.groups.iter().any(|group| claim_has(&claims["cognito:groups"], group)))
+            && (self.email_domains.is_empty() || verified_email_domain(claims).is_some_and(|domain| {
+                self.email_domains.iter().any(|d| d.eq_ignore_ascii_case(domain))
+            }))
+            && self.claims.iter().all(|(claim, values)| {
+                values.values().iter().any(|value| claim_matches(&claims[claim.as_str()], value, segments))
+            })
+    }
+}
+
+impl fmt::Display for Decision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Decision::Allow => write!(f, "allow"),
+            Decision::Deny => write!(f, "deny"),
+        }
+    }
+}
+
+impl ClaimValues {
+    fn values(&self) -> &[String] {
+        match self {
+            ClaimValues::One(value) => std::slice::from_ref(value),
+            ClaimValues::Any(values) => values,
+        }
+    }
+}
+
+impl PathPattern {
+    /// Patterns covering `prefix` and everything below it.
+    fn prefix(prefix: &str) -> Result<Vec<Self>, String> {
+        let prefix = globset::escape(prefix.trim_end_matches('/'));
+        if prefix.is_empty() {
+            return Ok(vec![PathPattern::try_from("/**".to_string())?]);
+        }
+        Ok(vec![
+            PathPattern::try_from(prefix.clone())?,
+            PathPattern::try_from(format!("{}/**", prefix))?,
+        ])
+    }
+}
+
+impl TryFrom<String> for PathPattern {
+    type Error = String;
+
+    fn try_from(pattern: String) -> Result<Self, String> {
+        if !pattern.starts_with('/') {
+            return Err(format!("path {:?} must start with /", pattern));
+        }
+        let matcher = GlobBuilder::new(&pattern)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| format!("invalid path {:?}: {}", pattern, e))?
+            .compile_matcher();
+        Ok(PathPattern { pattern, matcher })
+    }
+}
+
+impl PartialEq for PathPattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.pattern == other.pattern
+    }
+}
+
+/// Turn one `ACCESS_RULES` entry into an allow rule for its groups followed
+/// by a deny rule for everyone else.
+fn parse_group_rule(rule: &str) -> Result<Vec<AccessRule>, String> {
+    let (target, groups) = rule.split_once('=').ok_or("expected <path> [METHODS]=<groups>")?;
+    let mut target = target.split_whitespace();
+    let path = target.next().ok_or("missing path")?;
+    let methods = target.next()
+        .map(|methods| methods.split(',').map(str::to_uppercase).collect())
+        .unwrap_or_default();
+    if target.next().is_some() {
+        return Err("unexpected text after the methods".into());
+    }
+    let groups: Vec<String> = groups.split(',').map(str::trim).filter(|g| !g.is_empty()).map(String::from).collect();
+
+    let covered = AccessRule {
+        paths: PathPattern::prefix(path)?,
+        methods,
+        ..AccessRule::default()
+    };
+    covered.validate()?;
+
+    if groups.iter().any(|group| group == ANY_USER) {
+        return Ok(vec![covered]);
+    }
+    let deny = AccessRule {
+        effect: Decision::Deny,
+        reason: Some(match groups.is_empty() {
+            true => format!("{} is closed to everyone", path),
+            false => format!("{} requires one of the groups: {}", path, groups.join(", ")),
+        }),
+        ..covered.clone()
+    };
+    if groups.is_empty() {
+        return Ok(vec![deny]);
+    }
+    Ok(vec![AccessRule { groups, ..covered }, deny])
+}
+
+/// The path as the protected website will most likely see it, so that
+/// `/public/../admin`, `//admin` or `/%61dmin` cannot slip past a rule for
+/// `/admin`.
+fn normalize_path(path: &str) -> String {
+    let decoded = percent_decode_str(path).decode_utf8_lossy();
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    let trailing_slash = decoded.ends_with('/') && !segments.is_empty();
+    format!("/{}{}", segments.join("/"), if trailing_slash { "/" } else { "" })
+}
+
+fn verified_email_domain(claims: &Value) -> Option<&str> {
+    // Cognito sends `email_verified` as a boolean or a string, and providers
+    // that leave it out vouch for nothing
+    let verified = matches!(&claims["email_verified"], Value::Bool(true))
+        || claims["email_verified"].as_str() == Some("true");
+    if !verified {
+        return None;
+    }
+    claims["email"].as_str()?.rsplit_once('@').map(|(_, domain)| domain)
+}
+
+fn claim_matches(actual: &Value, expected: &str, segments: &[&str]) -> bool {
+    if expected == "*" {
+        return !actual.is_null();
+    }
+    if expected.starts_with("{path.") {
+        return path_index(expected)
+            .and_then(|idx| segments.get(idx))
+            .is_some_and(|segment| claim_has(actual, segment));
+    }
+    claim_has(actual, expected)
+}
+
+/// The zero-based segment a `{path.N}` placeholder refers to.
+fn path_index(placeholder: &str) -> Option<usize> {
+    let n: usize = placeholder.strip_prefix("{path.")?.strip_suffix('}')?.parse().ok()?;
+    n.checked_sub(1)
+}
+
+/// Whether a claim is, or for lists contains, the value.
+fn claim_has(actual: &Value, expected: &str) -> bool {
+    match actual {
+        Value::String(value) => value == expected,
+        Value::Array(values) => values.iter().any(|value| claim_has(value, expected)),
+        Value::Number(value) => value.to_string() == expected,
+        Value::Bool(value) => value.to_string() == expected,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn policy(rules: &[&str]) -> AccessPolicy {
+        AccessPolicy {
+            rules: rules.iter().flat_map(|rule| parse_group_rule(rule).unwrap()).collect(),
+            ..AccessPolicy::default()
+        }
+    }
+
+    fn member_of(groups: &[&str]) -> Value {
+        json!({ "sub": "user-1", "cognito:groups": groups })
+    }
+
+    #[test]
+    fn test_parse_group_rule() {
+        let rules = parse_group_rule("/api post,Delete = editors, admins").unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].methods, ["POST", "DELETE"]);
+        assert_eq!(rules[0].groups, ["editors", "admins"]);
+        assert_eq!(rules[1].effect, Decision::Deny);
+        assert_eq!(rules[1].reason.as_deref(), Some("/api requires one of the groups: editors, admins"));
+
+        assert_eq!(parse_group_rule("/closed=").unwrap().len(), 1);
+        assert_eq!(parse_group_rule("/open=*").unwrap()[0].effect, Decision::Allow);
+
+        for bad in ["/admin", "admin=admins", "/api GET extra=admins", "/api G(ET=admins"] {
+            assert!(parse_group_rule(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_group_rules() {
+        let policy = policy(&[
+            "/admin=admins",
+            "/api POST,DELETE=editors,admins",
+            "/api=*",
+            "/internal=",
+        ]);
+        let get = Method::GET;
+        let post = Method::POST;
+
+        assert!(policy.check(&get, "/admin", &member_of(&["admins"])).is_ok());
+        assert!(policy.check(&get, "/admin/users", &member_of(&["staff", "admins"])).is_ok());
+        assert_eq!(
+            policy.check(&get, "/admin/users", &member_of(&["staff"])),
+            Err("/admin requires one of the groups: admins".to_string())
+        );
+
+        // Method specific rules come first, the catch-all lets readers in
+        assert!(policy.check(&post, "/api/items", &member_of(&["editors"])).is_ok());
+        assert!(policy.check(&post, "/api/items", &member_of(&[])).is_err());
+        assert!(policy.check(&get, "/api/items", &member_of(&[])).is_ok());
+
+        assert_eq!(
+            policy.check(&get, "/internal/metrics", &member_of(&["admins"])),
+            Err("/internal is closed to everyone".to_string())
+        );
+
+        // Prefixes match whole segments, the rest falls back to the default
+        assert!(policy.check(&get, "/administrator", &member_of(&[])).is_ok());
+        let closed = AccessPolicy { default: Decision::Deny, ..policy };
+        assert_eq!(
+            closed.check(&get, "/administrator", &member_of(&[])),
+            Err("No access rule allows GET /administrator".to_string())
+        );
+    }
+
+    #[test]
+    fn test_policy_file_rules() {
+        let policy: AccessPolicy = toml::from_str(r#"
+            default = "deny"
+
+            [[rules]]
+            name = "outsiders"
+            effect = "deny"
+            reason = "Company accounts only"
+            claims = { email_verified = "false" }
+
+            [[rules]]
+            name = "ops may change admin settings"
+            paths = ["/admin/**"]
+            methods = ["post"]
+            users = ["ops-bot"]
+
+            [[rules]]
+            name = "company staff read admin pages"
+            paths = ["/admin/**"]
+            methods = ["GET"]
+            email_domains = ["ourcompany.com"]
+
+            [[rules]]
+            name = "own tenant only"
+            paths = ["/tenants/*/**"]
+            claims = { "custom:tenant" = "{path.2}" }
+        "#).unwrap();
+        let get = Method::GET;
+        let post = Method::POST;
+        let staff = json!({ "sub": "u1", "email": "jane@OurCompany.com", "email_verified": true });
+        let unverified = json!({ "sub": "u2", "email": "mallory@ourcompany.com", "email_verified": "false" });
+        let unconfirmed = json!({ "sub": "u5", "email": "eve@ourcompany.com" });
+        let bot = json!({ "sub": "u3", "username": "ops-bot" });
+        let tenant = json!({ "sub": "u4", "custom:tenant": "acme" });
+
+        let decided_by = |method: &Method, path: &str, claims: &Value| {
+            let verdict = policy.evaluate(method, path, claims);
+            (verdict.decision, verdict.rule.map(|(idx, _)| idx + 1))
+        };
+
+        assert_eq!(decided_by(&get, "/admin/settings", &staff), (Decision::Allow, Some(3)));
+        assert_eq!(decided_by(&post, "/admin/settings", &staff), (Decision::Deny, None));
+        assert_eq!(decided_by(&post, "/admin/settings", &bot), (Decision::Allow, Some(2)));
+        assert_eq!(decided_by(&get, "/admin/settings", &unverified), (Decision::Deny, Some(1)));
+        assert_eq!(policy.check(&get, "/admin", &unverified), Err("Company accounts only".to_string()));
+        assert_eq!(decided_by(&get, "/admin/settings", &unconfirmed), (Decision::Deny, None));
+
+        assert_eq!(decided_by(&get, "/tenants/acme/reports", &tenant), (Decision::Allow, Some(4)));
+        assert_eq!(decided_by(&get, "/tenants/globex/reports", &tenant), (Decision::Deny, None));
+        assert_eq!(decided_by(&get, "/tenants", &tenant), (Decision::Deny, None));
+    }
+
+    #[test]
+    fn test_policy_file_errors() {
+        let dir = std::env::temp_dir().join(format!("authy-policy-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = |name: &str, text: &str| {
+            let path = dir.join(name);
+            std::fs::write(&path, text).unwrap();
+            path.to_str().unwrap().to_string()
+        };
+
+        let good = file("good.toml", "[[rules]]\npaths = [\"/**\"]\n");
+        assert_eq!(AccessPolicy::from_file(&good).unwrap().rules.len(), 1);
+
+        for (name, text, expected) in [
+            ("typo.toml", "[[rules]]\npath = [\"/admin\"]\n", "unknown field"),
+            ("relative.toml", "[[rules]]\npaths = [\"admin\"]\n", "must start with /"),
+            ("method.toml", "[[rules]]\nmethods = [\"G(ET\"]\n", "invalid method"),
+            ("placeholder.toml", "[[rules]]\nclaims = { tenant = \"{path.0}\" }\n", "invalid placeholder"),
+        ] {
+            let error = AccessPolicy::from_file(&file(name, text)).unwrap_err();
+            assert!(error.contains(expected), "{}: {}", name, error);
+        }
+        assert!(AccessPolicy::from_file("/nonexistent/policy.toml").unwrap_err().contains("Failed to read"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(normalize_path("/"), "/");
+        assert_eq!(normalize_path("/admin/users"), "/admin/users");
+        assert_eq!(normalize_path("/docs/"), "/docs/");
+        assert_eq!(normalize_path("/public/../admin"), "/admin");
+        assert_eq!(normalize_path("//admin/./users"), "/admin/users");
+        assert_eq!(normalize_path("/%61dmin"), "/admin");
+        assert_eq!(normalize_path("/../../etc"), "/etc");
+
+        let policy = policy(&["/admin=admins"]);
+        for path in ["/public/../admin", "//admin", "/%61dmin/users"] {
+            assert!(policy.check(&Method::GET, path, &member_of(&[])).is_err(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_path_prefix_patterns() {
+        let matches = |prefix: &str, path: &str| {
+            PathPattern::prefix(prefix).unwrap().iter().any(|pattern| pattern.matcher.is_match(path))
+        };
+        assert!(matches("/", "/"));
+        assert!(matches("/", "/anything/below"));
+        assert!(matches("/docs/", "/docs"));
+        assert!(matches("/docs", "/docs/intro"));
+        assert!(!matches("/docs", "/docsearch"));
+        assert!(!matches("/docs", "/doc"));
+        // Glob characters in a prefix are taken literally
+        assert!(!matches("/files/*", "/files/secret"));
+    }
+}
@@ -0,0 +1,130 @@
+use std::env;
+
+use axum::http::Method;
+
+use super::{normalize_path, PathPattern};
+
+/// How much of a session a route asks for.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum RouteClass {
+    /// Proxied without looking at the session
+    Public,
+    /// Proxied for everyone, with the user's identity when signed in
+    Optional,
+    /// Only for signed-in users the access policy lets through
+    #[default]
+    Protected,
+}
+
+/// Routes that do not need a session, everything else being protected.
+#[derive(Clone, Debug, Default)]
+pub struct RouteTable {
+    routes: Vec<Route>,
+    /// Problems reading the routes, reported by `validate`
+    errors: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+struct Route {
+    class: RouteClass,
+    path: PathPattern,
+    methods: Vec<Method>,
+}
+
+impl RouteTable {
+    /// Read `PUBLIC_ROUTES` and `OPTIONAL_ROUTES`.
+    ///
+    /// Both hold `;` separated `<path glob> [METHOD,...]` entries, e.g.
+    /// `/favicon.ico; /static/**; /webhooks/** POST`.
+    pub fn from_env() -> Self {
+        let mut table = RouteTable::default();
+        for (var, class) in [("PUBLIC_ROUTES", RouteClass::Public), ("OPTIONAL_ROUTES", RouteClass::Optional)] {
+            let Ok(routes) = env::var(var) else {
+                continue;
+            };
+            for route in routes.split(';').map(str::trim).filter(|route| !route.is_empty()) {
+                if let Err(e) = table.add(class, route) {
+                    table.errors.push(format!("{} entry {:?}: {}", var, route, e));
+                }
+            }
+        }
+        table
+    }
+
+    /// Add a `<path glob> [METHOD,...]` route.
+    pub fn add(&mut self, class: RouteClass, route: &str) -> Result<(), String> {
+        let mut parts = route.split_whitespace();
+        let path = PathPattern::try_from(parts.next().ok_or("missing path")?.to_string())?;
+        let methods = match parts.next() {
+            Some(methods) => methods
+                .split(',')
+                .map(|m| Method::from_bytes(m.to_uppercase().as_bytes()).map_err(|_| format!("invalid method {:?}", m)))
+                .collect::<Result<_, _>>()?,
+            None => Vec::new(),
+        };
+        if parts.next().is_some() {
+            return Err("unexpected text after the methods".into());
+        }
+        self.routes.push(Route { class, path, methods });
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match self.errors.first() {
+            Some(error) => Err(error.clone()),
+            None => Ok(()),
+        }
+    }
+
+    /// The class of the first route matching the request. Public routes are
+    /// tried before optional ones.
+    pub fn classify(&self, method: &Method, path: &str) -> RouteClass {
+        let path = normalize_path(path);
+        [RouteClass::Public, RouteClass::Optional]
+            .into_iter()
+            .find(|class| {
+                self.routes.iter().any(|route| {
+                    route.class == *class
+                        && route.path.matcher.is_match(&path)
+                        && (route.methods.is_empty() || route.methods.contains(method))
+                })
+            })
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        let mut table = RouteTable::default();
+        table.add(RouteClass::Optional, "/blog/**").unwrap();
+        table.add(RouteClass::Public, "/favicon.ico").unwrap();
+        table.add(RouteClass::Public, "/static/**").unwrap();
+        table.add(RouteClass::Public, "/webhooks/* post").unwrap();
+        table.add(RouteClass::Public, "/blog/feed.xml GET,HEAD").unwrap();
+
+        assert_eq!(table.classify(&Method::GET, "/favicon.ico"), RouteClass::Public);
+        assert_eq!(table.classify(&Method::GET, "/static/css/site.css"), RouteClass::Public);
+        assert_eq!(table.classify(&Method::POST, "/webhooks/stripe"), RouteClass::Public);
+        assert_eq!(table.classify(&Method::GET, "/webhooks/stripe"), RouteClass::Protected);
+        assert_eq!(table.classify(&Method::POST, "/webhooks/stripe/replay"), RouteClass::Protected);
+
+        // Public routes win over optional ones listed earlier
+        assert_eq!(table.classify(&Method::GET, "/blog/feed.xml"), RouteClass::Public);
+        assert_eq!(table.classify(&Method::GET, "/blog/2024/hello"), RouteClass::Optional);
+
+        assert_eq!(table.classify(&Method::GET, "/"), RouteClass::Protected);
+        assert_eq!(table.classify(&Method::GET, "/static/../admin"), RouteClass::Protected);
+    }
+
+    #[test]
+    fn test_add_invalid() {
+        let mut table = RouteTable::default();
+        for bad in ["static/**", "/static/[", "/hooks G(ET", "/hooks POST extra"] {
+            assert!(table.add(RouteClass::Public, bad).is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,298 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::{
+    auth::TokenResponse,
+    config::{ProviderConfig, ProviderKind},
+    error::AppError,
+    jwks::JwksCache,
+};
+
+/// The endpoints of an identity provider, as published in its OpenID
+/// Connect discovery document.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct ProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    #[serde(default)]
+    pub userinfo_endpoint: Option<String>,
+    #[serde(default)]
+    pub end_session_endpoint: Option<String>,
+    #[serde(default)]
+    pub revocation_endpoint: Option<String>,
+    pub jwks_uri: String,
+}
+
+impl ProviderMetadata {
+    /// The Cognito preset. Cognito does publish a discovery document, but it
+    /// lives on the `cognito-idp` host and does not point at the hosted UI
+    /// `/login` and `/logout` pages we want, so the endpoints are spelled out.
+    pub fn cognito(domain: &str, issuer: String) -> Self {
+        let domain = domain.trim_end_matches('/');
+        ProviderMetadata {
+            authorization_endpoint: format!("{}/login", domain),
+            token_endpoint: format!("{}/oauth2/token", domain),
+            userinfo_endpoint: Some(format!("{}/oauth2/userInfo", domain)),
+            end_session_endpoint: Some(format!("{}/logout", domain)),
+            revocation_endpoint: Some(format!("{}/oauth2/revoke", domain)),
+            jwks_uri: format!("{}/.well-known/jwks.json", issuer),
+            issuer,
+        }
+    }
+
+    /// Fetch `/.well-known/openid-configuration` for an issuer.
+    pub async fn discover(issuer: &str) -> Result<Self, AppError> {
+        let issuer = issuer.trim_end_matches('/');
+        let url = format!("{}/.well-known/openid-configuration", issuer);
+
+        let response = reqwest::get(&url).await?;
+        if !response.status().is_success() {
+            return Err(AppError::Internal(format!(
+                "OpenID discovery at {} returned {}",
+                url,
+                response.status()
+            )));
+        }
+
+        let metadata = response.json::<ProviderMetadata>().await?;
+        // The document must describe the issuer we asked about (OIDC Discovery 4.3)
+        if metadata.issuer.trim_end_matches('/') != issuer {
+            return Err(AppError::Internal(format!(
+                "OpenID discovery issuer mismatch: expected {}, got {}",
+                issuer, metadata.issuer
+            )));
+        }
+
+        Ok(metadata)
+    }
+}
+
+/// A configured identity provider together with its endpoints and keys.
+#[derive(Clone)]
+pub struct Provider {
+    pub config: ProviderConfig,
+    pub metadata: ProviderMetadata,
+    pub jwks: JwksCache,
+}
+
+impl Provider {
+    pub async fn new(config: ProviderConfig, jwks_refresh_interval: Duration) -> Result<Self, AppError> {
+        let metadata = match &config.kind {
+            ProviderKind::Cognito { domain, identity_provider, .. } => {
+                let mut metadata = ProviderMetadata::cognito(domain, config.kind.issuer());
+                // `/login` always shows the hosted UI, only `/oauth2/authorize`
+                // honours the `identity_provider` hint
+                if identity_provider.is_some() {
+                    metadata.authorization_endpoint = format!("{}/oauth2/authorize", domain.trim_end_matches('/'));
+                }
+                metadata
+            }
+            ProviderKind::Oidc { issuer } => ProviderMetadata::discover(issuer).await?,
+        };
+        let jwks = JwksCache::new(metadata.jwks_uri.clone(), jwks_refresh_interval);
+
+        Ok(Provider { config, metadata, jwks })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    pub fn is_cognito(&self) -> bool {
+        matches!(self.config.kind, ProviderKind::Cognito { .. })
+    }
+
+    /// The `redirect_uri` registered with the provider.
+    pub fn redirect_uri(&self, server_domain: &str) -> String {
+        format!("{}{}", server_domain.trim_end_matches('/'), self.config.callback_path())
+    }
+
+    /// The authorization endpoint with our client and any provider hint filled in.
+    pub fn authorization_url(&self, server_domain: &str) -> Result<Url, AppError> {
+        let mut url = Url::parse(&self.metadata.authorization_endpoint)
+            .map_err(|e| AppError::Internal(format!("Invalid authorization endpoint: {}", e)))?;
+
+        url.query_pairs_mut()
+            .append_pair("client_id", &self.config.client_id)
+            .append_pair("response_type", "code")
+            .append_pair("scope", &self.config.scopes)
+            .append_pair("redirect_uri", &self.redirect_uri(server_domain));
+        if let ProviderKind::Cognito { identity_provider: Some(idp), .. } = &self.config.kind {
+            url.query_pairs_mut().append_pair("identity_provider", idp);
+        }
+        Ok(url)
+    }
+
+    /// The token kept in the session cookie.
+    ///
+    /// Cognito access tokens are JWTs scoped to our client. Other providers
+    /// may hand out opaque access tokens or ones meant for another audience,
+    /// so the ID token is used for them.
+    pub fn session_token<'a>(&self, tokens: &'a TokenResponse) -> &'a str {
+        match (&self.config.kind, &tokens.id_token) {
+            (ProviderKind::Oidc { .. }, Some(id_token)) => id_token,
+            _ => &tokens.access_token,
+        }
+    }
+
+    /// Where to send the browser to sign out at the provider.
+    pub fn logout_url(&self, logout_uri: &str) -> String {
+        let Some(end_session) = &self.metadata.end_session_endpoint else {
+            // Nothing to sign out of upstream, go straight to the landing page
+            return logout_uri.to_string();
+        };
+        let Ok(mut url) = Url::parse(end_session) else {
+            return logout_uri.to_string();
+        };
+
+        // Cognito predates RP-initiated logout and names the parameter differently
+        let redirect_param = if self.is_cognito() { "logout_uri" } else { "post_logout_redirect_uri" };
+        url.query_pairs_mut()
+            .append_pair("client_id", &self.config.client_id)
+            .append_pair(redirect_param, logout_uri);
+        url.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    /// A discovery document for a mock issuer served by `mock_server`.
+    pub fn discovery_document(issuer: &str) -> serde_json::Value {
+        serde_json::json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{}/protocol/openid-connect/auth", issuer),
+            "token_endpoint": format!("{}/protocol/openid-connect/token", issuer),
+            "userinfo_endpoint": format!("{}/protocol/openid-connect/userinfo", issuer),
+            "end_session_endpoint": format!("{}/protocol/openid-connect/logout", issuer),
+            "jwks_uri": format!("{}/protocol/openid-connect/certs", issuer),
+            "response_types_supported": ["code"],
+            "subject_types_supported": ["public"],
+            "id_token_signing_alg_values_supported": ["RS256"]
+        })
+    }
+
+    fn oidc_config(issuer: String) -> ProviderConfig {
+        ProviderConfig {
+            name: "oidc".to_string(),
+            label: "OIDC".to_string(),
+            kind: ProviderKind::Oidc { issuer },
+            client_id: "oidc-client".to_string(),
+            client_secret: String::new(),
+            scopes: "openid email".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_discovery() {
+        let mock_server = MockServer::start().await;
+        let issuer = format!("{}/realms/main", mock_server.uri());
+
+        Mock::given(method("GET"))
+            .and(path("/realms/main/.well-known/openid-configuration"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(discovery_document(&issuer)))
+            .mount(&mock_server)
+            .await;
+
+        let provider = Provider::new(oidc_config(format!("{}/", issuer)), Duration::from_secs(3600))
+            .await
+            .unwrap();
+        assert_eq!(provider.metadata.issuer, issuer);
+        assert_eq!(provider.metadata.token_endpoint, format!("{}/protocol/openid-connect/token", issuer));
+        assert_eq!(provider.metadata.revocation_endpoint, None);
+        assert!(!provider.is_cognito());
+    }
+
+    #[tokio::test]
+    async fn test_discovery_rejects_issuer_mismatch() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/.well-known/openid-configuration"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(discovery_document("https://evil.example.com")))
+            .mount(&mock_server)
+            .await;
+
+        let result = ProviderMetadata::discover(&mock_server.uri()).await;
+        assert!(matches!(result, Err(AppError::Internal(msg)) if msg.contains("issuer mismatch")));
+    }
+
+    #[tokio::test]
+    async fn test_cognito_preset() {
+        let provider = Provider::new(
+            ProviderConfig::for_tests("https://test.auth.amazoncognito.com/"),
+            Duration::from_secs(3600),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(provider.metadata.issuer, "https://cognito-idp.us-east-1.amazonaws.com/us-east-1_test");
+        assert_eq!(provider.metadata.authorization_endpoint, "https://test.auth.amazoncognito.com/login");
+        assert_eq!(provider.metadata.token_endpoint, "https://test.auth.amazoncognito.com/oauth2/token");
+        assert_eq!(
+            provider.metadata.jwks_uri,
+            "https://cognito-idp.us-east-1.amazonaws.com/us-east-1_test/.well-known/jwks.json"
+        );
+        assert_eq!(
+            provider.logout_url("http://localhost:3000/"),
+            "https://test.auth.amazoncognito.com/logout?client_id=test-client-id&logout_uri=http%3A%2F%2Flocalhost%3A3000%2F"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_oidc_logout_and_session_token() {
+        let mock_server = MockServer::start().await;
+        let issuer = mock_server.uri();
+
+        Mock::given(method("GET"))
+            .and(path("/.well-known/openid-configuration"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(discovery_document(&issuer)))
+            .mount(&mock_server)
+            .await;
+
+        let provider = Provider::new(oidc_config(issuer.clone()), Duration::from_secs(3600)).await.unwrap();
+        assert_eq!(
+            provider.logout_url("http://localhost:3000/"),
+            format!(
+                "{}/protocol/openid-connect/logout?client_id=oidc-client&post_logout_redirect_uri=http%3A%2F%2Flocalhost%3A3000%2F",
+                issuer
+            )
+        );
+
+        let mut tokens = TokenResponse {
+            access_token: "opaque-access-token".to_string(),
+            token_type: "Bearer".to_string(),
+            expires_in: 300,
+            id_token: Some("id-token".to_string()),
+            refresh_token: None,
+        };
+        assert_eq!(provider.session_token(&tokens), "id-token");
+        tokens.id_token = None;
+        assert_eq!(provider.session_token(&tokens), "opaque-access-token");
+    }
+
+    #[tokio::test]
+    async fn test_cognito_identity_provider_hint() {
+        let mut config = ProviderConfig::for_tests("https://test.auth.amazoncognito.com");
+        config.name = "partners".to_string();
+        if let ProviderKind::Cognito { identity_provider, .. } = &mut config.kind {
+            *identity_provider = Some("PartnerSAML".to_string());
+        }
+
+        let provider = Provider::new(config, Duration::from_secs(3600)).await.unwrap();
+        let url = provider.authorization_url("http://localhost:3000").unwrap();
+        assert!(url.as_str().starts_with("https://test.auth.amazoncognito.com/oauth2/authorize?"));
+
+        let query: std::collections::HashMap<_, _> = url.query_pairs().into_owned().collect();
+        assert_eq!(query["identity_provider"], "PartnerSAML");
+        assert_eq!(query["redirect_uri"], "http://localhost:3000/callback/partners");
+    }
+}
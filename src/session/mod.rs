@@ -1,87 +1,301 @@
+pub mod sealed;
+
+use std::sync::Arc;
+
 use axum::{
-    body::Body,
-    http::Request,
+    http::{HeaderMap, Request},
     extract::ConnectInfo,
 };
 use cookie::{Cookie, CookieJar};
-use jsonwebtoken::{decode, decode_header, DecodingKey, Validation};
 use serde::{Deserialize, Serialize};
-use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::error::AppError;
+use crate::{
+    auth::{pkce::random_urlsafe, TokenResponse},
+    config::Config,
+    error::AppError,
+    provider::Provider,
+    state::AppState,
+    store::{SessionRecord, SessionStore},
+    verifier::TokenError,
+};
+
+pub use self::sealed::SessionSealer;
 
-const SESSION_COOKIE_NAME: &str = "authy_session";
+/// Longest value put in one cookie, staying under the 4096 bytes browsers
+/// allow per cookie with room for the name and attributes.
+const MAX_COOKIE_VALUE_LEN: usize = 3800;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Most chunks written or read back, bounding what one request can make us
+/// assemble.
+const MAX_COOKIE_CHUNKS: usize = 16;
+
+/// Renew the access token when it has less than this many seconds left.
+const REFRESH_BEFORE_EXPIRY_SECS: u64 = 60;
+
+/// Record activity for the idle timeout at most this often, rather than
+/// rewriting the session on every request.
+const TOUCH_INTERVAL_SECS: u64 = 60;
+
+/// Claims of a Cognito access or ID token, or an OpenID Connect ID token.
+///
+/// Cognito access tokens carry `client_id` and no `aud`, ID tokens the
+/// other way around; `token_use` says which one we are looking at.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,
     pub exp: u64,
     pub iat: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nbf: Option<u64>,
     pub iss: String,
-    pub aud: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aud: Option<Audience>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub azp: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_use: Option<String>,
+    /// Cognito user pool groups the user belongs to
+    #[serde(rename = "cognito:groups", default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    /// `username` in Cognito access tokens, `cognito:username` in ID tokens
+    #[serde(default, alias = "cognito:username", skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    /// Every other claim, for access rules on custom attributes
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// The `aud` claim, which may be a single string or an array (RFC 7519 4.1.3).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Audience {
+    pub fn contains(&self, audience: &str) -> bool {
+        match self {
+            Audience::One(aud) => aud == audience,
+            Audience::Many(auds) => auds.iter().any(|aud| aud == audience),
+        }
+    }
+}
+
+/// Where session records live between requests.
+#[derive(Clone)]
+pub enum Sessions {
+    /// In a store, the cookie only carrying the record's ID
+    Store(Arc<dyn SessionStore>),
+    /// Sealed into the cookie itself
+    Cookie(SessionSealer),
+}
+
+impl Sessions {
+    /// The server-side store, unless sessions live in cookies.
+    pub fn store(&self) -> Option<&Arc<dyn SessionStore>> {
+        match self {
+            Sessions::Store(store) => Some(store),
+            Sessions::Cookie(_) => None,
+        }
+    }
+
+    async fn load(&self, value: &str, now: u64) -> Result<Option<SessionRecord>, AppError> {
+        match self {
+            Sessions::Store(store) => store.load(value, now).await,
+            Sessions::Cookie(sealer) => Ok(sealer.open(value, now)),
+        }
+    }
+
+    /// Save the record, returning the new cookie value if it changed.
+    async fn save(&self, id: Option<&str>, record: &SessionRecord) -> Result<String, AppError> {
+        match self {
+            Sessions::Store(store) => {
+                let id = id.map(str::to_owned).unwrap_or_else(|| random_urlsafe(32));
+                store.save(&id, record).await?;
+                Ok(id)
+            }
+            Sessions::Cookie(sealer) => sealer.seal(record),
+        }
+    }
 }
 
 pub struct Session {
     pub claims: Claims,
+    /// Name of the provider that issued the session token
+    pub provider: String,
+    /// The user's current tokens, for relaying to the protected website
+    pub access_token: String,
+    pub id_token: Option<String>,
+    /// Cookies to set on the response
+    pub cookies: Vec<Cookie<'static>>,
 }
 
-pub async fn validate_session(req: Request<Body>) -> Result<(Session, Request<Body>), AppError> {
-    // Skip JWT validation in tests
-    if cfg!(test) {
-        let client_ip = req.headers()
-            .get("x-forwarded-for")
-            .and_then(|h| h.to_str().ok())
-            .map(|s| s.to_string())
-            .unwrap_or_else(|| "unknown".to_string());
-
-        let path = req.uri().path().to_string();
-
-        // Check for cookie in tests
-        let cookies = req.headers()
-            .get("cookie")
-            .and_then(|v| v.to_str().ok())
-            .map(|cookie_str| {
-                let mut jar = CookieJar::new();
-                cookie_str.split(';')
-                    .filter_map(|s| Cookie::parse(s.trim().to_owned()).ok())
-                    .for_each(|cookie| jar.add_original(cookie));
-                jar
-            })
-            .ok_or_else(|| AppError::Unauthorized {
-                message: "No session cookie found".into(),
-                client_ip: client_ip.clone(),
-                path: path.clone(),
-            })?;
-
-        let session_cookie = cookies
-            .get(SESSION_COOKIE_NAME)
-            .ok_or_else(|| AppError::Unauthorized {
-                message: "No session cookie found".into(),
-                client_ip: client_ip.clone(),
-                path: path.clone(),
-            })?;
-
-        let token = session_cookie.value();
-        if token == "invalid.token.here" {
-            return Err(AppError::Unauthorized {
-                message: "Invalid token header".into(),
-                client_ip,
-                path,
-            });
-        }
+/// Verify freshly issued tokens and store them as a new session.
+///
+/// Returns the session cookies, carrying either the ID of the stored record
+/// or the sealed record itself, along with removals for chunks a previous
+/// session in `headers` left behind. Remembered sessions get the longer
+/// maximum age, if one is configured.
+pub async fn start_session(
+    state: &AppState,
+    provider: &Provider,
+    tokens: &TokenResponse,
+    remember: bool,
+    headers: &HeaderMap,
+) -> Result<Vec<Cookie<'static>>, AppError> {
+    let claims = verify_tokens(state, provider, tokens)
+        .await
+        .map_err(|e| match e {
+            TokenError::Expired => AppError::Auth("Issued token already expired".into()),
+            TokenError::Invalid(message) => AppError::Auth(message),
+        })?;
+
+    let lifetime = &state.config.session_lifetime;
+    let now = state.clock.now();
+    let record = SessionRecord::new(provider.name(), tokens, claims, lifetime, remember, now);
+    let value = state.sessions.save(None, &record).await?;
 
-        return Ok((Session {
-            claims: Claims {
-                sub: "test".to_string(),
-                exp: 9999999999,
-                iat: 1516239022,
-                iss: "https://test.auth.amazoncognito.com".to_string(),
-                aud: "your-app-client-id".to_string(),
+    create_session_cookies(&state.config, &value, record.expires_at, now, headers)
+}
+
+/// Remove the session the request belongs to, returning it if there was one.
+pub async fn end_session(state: &AppState, headers: &HeaderMap) -> Result<Option<SessionRecord>, AppError> {
+    let Some(value) = session_cookie_value(&state.config, headers) else {
+        return Ok(None);
+    };
+
+    let record = state.sessions.load(&value, state.clock.now()).await?;
+    // A sealed cookie has nothing to delete besides the cookie itself
+    if let Some(store) = state.sessions.store() {
+        store.delete(&value).await?;
+    }
+    Ok(record)
+}
+
+pub async fn validate_session<B>(
+    state: &AppState,
+    req: &Request<B>,
+) -> Result<Session, AppError> {
+    let client_ip = client_ip(req);
+    let path = req.uri().path().to_string();
+    let unauthorized = |message: String| AppError::Unauthorized {
+        message,
+        client_ip: client_ip.clone(),
+        path: path.clone(),
+    };
+
+    // Extract session cookie
+    let value = session_cookie_value(&state.config, req.headers())
+        .ok_or_else(|| unauthorized("No session cookie found".into()))?;
+
+    // Unknown, undecryptable, idle and expired sessions all just mean
+    // signing in again
+    let now = state.clock.now();
+    let mut record = state
+        .sessions
+        .load(&value, now)
+        .await?
+        .ok_or_else(|| unauthorized("Session expired or not found".into()))?;
+
+    let provider = state
+        .provider(&record.provider)
+        .ok_or_else(|| unauthorized("Unknown identity provider".into()))?;
+    let session = |record: SessionRecord, cookies| Session {
+        claims: record.claims,
+        provider: provider.name().to_string(),
+        access_token: record.access_token,
+        id_token: record.id_token,
+        cookies,
+    };
+
+    let mut changed = false;
+    if record.idle_timeout.is_some() && now >= record.last_seen + TOUCH_INTERVAL_SECS {
+        record.touch(now);
+        changed = true;
+    }
+
+    if expires_soon(&record.claims, now) {
+        // Renew the tokens if we can, otherwise keep using them while they last
+        let refreshed = match &record.refresh_token {
+            Some(refresh_token) => state.refresher.refresh(provider, refresh_token).await
+                .map_err(|e| tracing::warn!("Failed to refresh session for path={}: {}", path, e))
+                .ok(),
+            None => None,
+        };
+
+        match refreshed {
+            // OIDC providers may leave the ID token out of a refresh, the
+            // one we verified at sign-in still says who the user is
+            Some(tokens) if !provider.is_cognito() && tokens.id_token.is_none() => {
+                let claims = Claims { exp: now + u64::from(tokens.expires_in), ..record.claims.clone() };
+                record.update(&tokens, claims);
+                changed = true;
             }
-        }, req));
+            Some(tokens) => {
+                let claims = verify_tokens(state, provider, &tokens)
+                    .await
+                    .map_err(|e| match e {
+                        TokenError::Expired => unauthorized("Refreshed token already expired".into()),
+                        TokenError::Invalid(message) => unauthorized(message),
+                    })?;
+                record.update(&tokens, claims);
+                changed = true;
+            }
+            // Past the token's expiry, allowing for clock skew with the provider
+            None if record.claims.exp + state.config.token_leeway <= now => {
+                return Err(unauthorized("Token expired".into()));
+            }
+            None => {}
+        }
+    }
+
+    if !changed {
+        return Ok(session(record, Vec::new()));
     }
-    // Get client IP
-    let client_ip = req.headers()
+
+    // Reissue the cookie so its expiry follows the session's
+    let value = state.sessions.save(Some(&value), &record).await?;
+    let cookies = create_session_cookies(&state.config, &value, record.expires_at, now, req.headers())?;
+    Ok(session(record, cookies))
+}
+
+/// Verify the session token, completing its claims from the ID token.
+///
+/// Cognito sessions keep the access token, which carries the groups but not
+/// the user's email, so a separate ID token is verified as well and fills in
+/// what the session token lacks.
+async fn verify_tokens(state: &AppState, provider: &Provider, tokens: &TokenResponse) -> Result<Claims, TokenError> {
+    let session_token = provider.session_token(tokens);
+    let mut claims = state.verify(provider, session_token).await?;
+
+    if let Some(id_token) = tokens.id_token.as_deref().filter(|id_token| *id_token != session_token) {
+        let profile = state.verify(provider, id_token).await?;
+        if profile.sub != claims.sub {
+            return Err(TokenError::Invalid("ID token belongs to another user".into()));
+        }
+        claims.email = claims.email.or(profile.email);
+        claims.username = claims.username.or(profile.username);
+        if claims.groups.is_empty() {
+            claims.groups = profile.groups;
+        }
+        for (name, value) in profile.extra {
+            claims.extra.entry(name).or_insert(value);
+        }
+    }
+    Ok(claims)
+}
+
+fn expires_soon(claims: &Claims, now: u64) -> bool {
+    claims.exp <= now + REFRESH_BEFORE_EXPIRY_SECS
+}
+
+pub fn client_ip<B>(req: &Request<B>) -> String {
+    req.headers()
         .get("x-forwarded-for")
         .and_then(|h| h.to_str().ok())
         .map(|s| s.to_string())
@@ -90,10 +304,12 @@ pub async fn validate_session(req: Request<Body>) -> Result<(Session, Request<Bo
                 .get::<ConnectInfo<std::net::SocketAddr>>()
                 .map(|addr| addr.ip().to_string())
                 .unwrap_or_else(|| "unknown".to_string())
-        });
+        })
+}
 
-    // Extract session cookie
-    let cookies = req.headers()
+/// Parse the `Cookie` request header into a jar, if one was sent.
+pub fn parse_cookies(headers: &HeaderMap) -> Option<CookieJar> {
+    headers
         .get("cookie")
         .and_then(|v| v.to_str().ok())
         .map(|cookie_str| {
@@ -103,128 +319,221 @@ pub async fn validate_session(req: Request<Body>) -> Result<(Session, Request<Bo
                 .for_each(|cookie| jar.add_original(cookie));
             jar
         })
-        .ok_or_else(|| AppError::Unauthorized {
-            message: "No session cookie found".into(),
-            client_ip: client_ip.clone(),
-            path: req.uri().path().to_string(),
-        })?;
+}
 
-    let session_cookie = cookies
-        .get(SESSION_COOKIE_NAME)
-        .ok_or_else(|| AppError::Unauthorized {
-            message: "No session cookie found".into(),
-            client_ip: client_ip.clone(),
-            path: req.uri().path().to_string(),
-        })?;
+/// The session cookie's value, reassembled from its chunks if it was split.
+fn session_cookie_value(config: &Config, headers: &HeaderMap) -> Option<String> {
+    let jar = parse_cookies(headers)?;
+    let name = config.session_cookie.full_name();
+    if let Some(cookie) = jar.get(&name).filter(|cookie| !cookie.value().is_empty()) {
+        return Some(cookie.value().to_owned());
+    }
 
-    let token = session_cookie.value();
+    // Chunks are numbered from 0 without gaps
+    let value: String = (0..MAX_COOKIE_CHUNKS)
+        .map_while(|index| jar.get(&chunk_name(&name, index)).map(|cookie| cookie.value().to_owned()))
+        .collect();
+    Some(value).filter(|value| !value.is_empty())
+}
 
-    // Get the key ID from the token header
-    let header = decode_header(token)
-        .map_err(|e| AppError::Unauthorized {
-            message: format!("Invalid token header: {}", e),
-            client_ip: client_ip.clone(),
-            path: req.uri().path().to_string(),
-        })?;
+fn chunk_name(name: &str, index: usize) -> String {
+    format!("{}.{}", name, index)
+}
 
-    let kid = header.kid
-        .ok_or_else(|| AppError::Unauthorized {
-            message: "No key ID in token".into(),
-            client_ip: client_ip.clone(),
-            path: req.uri().path().to_string(),
-        })?;
+/// Names of the session cookies and chunks the browser sent.
+fn sent_session_cookies(config: &Config, headers: &HeaderMap) -> Vec<String> {
+    let name = config.session_cookie.full_name();
+    let chunk_prefix = format!("{}.", name);
+    let mut names: Vec<_> = parse_cookies(headers)
+        .map(|jar| {
+            jar.iter()
+                .map(|cookie| cookie.name().to_owned())
+                .filter(|sent| *sent == name || sent.starts_with(&chunk_prefix))
+                .collect()
+        })
+        .unwrap_or_default();
+    names.sort();
+    names
+}
 
-    // Fetch the JWK for this key ID from Cognito
-    // In production, you should cache these keys and refresh periodically
-    let jwks_url = format!("{}/.well-known/jwks.json", std::env::var("COGNITO_DOMAIN").unwrap());
-    let jwks = reqwest::get(&jwks_url)
-        .await?
-        .json::<serde_json::Value>()
-        .await?;
-
-    let matching_key = jwks["keys"]
-        .as_array()
-        .ok_or_else(|| AppError::Unauthorized {
-            message: "Invalid JWKS format".into(),
-            client_ip: client_ip.clone(),
-            path: req.uri().path().to_string(),
-        })?
-        .iter()
-        .find(|key| key["kid"].as_str() == Some(&kid))
-        .ok_or_else(|| AppError::Unauthorized {
-            message: "No matching key found".into(),
-            client_ip: client_ip.clone(),
-            path: req.uri().path().to_string(),
-        })?;
+/// Cookies carrying `value` from `now` until `expires_at`, split across
+/// `<name>.0`, `.1`, ... when it is too large for one, plus removals for
+/// any session cookies in `headers` they do not overwrite.
+///
+/// Fails for values needing more than `MAX_COOKIE_CHUNKS` chunks, which
+/// could not be read back.
+pub fn create_session_cookies(
+    config: &Config,
+    value: &str,
+    expires_at: u64,
+    now: u64,
+    headers: &HeaderMap,
+) -> Result<Vec<Cookie<'static>>, AppError> {
+    if value.len() > MAX_COOKIE_VALUE_LEN * MAX_COOKIE_CHUNKS {
+        return Err(AppError::Internal(format!(
+            "Session of {} bytes does not fit in {} cookies",
+            value.len(),
+            MAX_COOKIE_CHUNKS
+        )));
+    }
+    let name = config.session_cookie.full_name();
+    let max_age = cookie::time::Duration::seconds(expires_at.saturating_sub(now) as i64);
+    let mut cookies = if value.len() <= MAX_COOKIE_VALUE_LEN {
+        vec![session_cookie(config, name, value.to_owned(), max_age)]
+    } else {
+        // Session values are ASCII, so splitting on bytes is safe
+        value
+            .as_bytes()
+            .chunks(MAX_COOKIE_VALUE_LEN)
+            .enumerate()
+            .map(|(index, chunk)| {
+                let value = String::from_utf8_lossy(chunk).into_owned();
+                session_cookie(config, chunk_name(&name, index), value, max_age)
+            })
+            .collect()
+    };
 
-    // Create decoding key from the JWK
-    let n = matching_key["n"].as_str()
-        .ok_or_else(|| AppError::Unauthorized {
-            message: "Invalid key format".into(),
-            client_ip: client_ip.clone(),
-            path: req.uri().path().to_string(),
-        })?;
-    let e = matching_key["e"].as_str()
-        .ok_or_else(|| AppError::Unauthorized {
-            message: "Invalid key format".into(),
-            client_ip: client_ip.clone(),
-            path: req.uri().path().to_string(),
-        })?;
+    let stale: Vec<_> = sent_session_cookies(config, headers)
+        .into_iter()
+        .filter(|name| cookies.iter().all(|cookie| cookie.name() != name))
+        .map(|name| removal_cookie(config, name))
+        .collect();
+    cookies.extend(stale);
+    Ok(cookies)
+}
 
-    let decoding_key = DecodingKey::from_rsa_components(n, e)
-        .map_err(|e| AppError::Unauthorized {
-            message: format!("Invalid key components: {}", e),
-            client_ip: client_ip.clone(),
-            path: req.uri().path().to_string(),
-        })?;
+fn session_cookie(config: &Config, name: String, value: String, max_age: cookie::time::Duration) -> Cookie<'static> {
+    let mut cookie = Cookie::new(name, value);
+    cookie.set_max_age(max_age);
+    cookie.set_http_only(true);
+    cookie.set_same_site(Some(config.session_cookie.same_site.into()));
+    set_scope(config, &mut cookie);
+    cookie
+}
 
-    // Validate the token
-    let mut validation = Validation::new(jsonwebtoken::Algorithm::RS256);
-    validation.set_audience(&["your-app-client-id"]); // Set this to your Cognito app client ID
-    validation.set_issuer(&[std::env::var("COGNITO_DOMAIN").unwrap()]);
-
-    let token_data = decode::<Claims>(
-        token,
-        &decoding_key,
-        &validation
-    ).map_err(|e| AppError::Unauthorized {
-        message: format!("Invalid token: {}", e),
-        client_ip: client_ip.clone(),
-        path: req.uri().path().to_string(),
-    })?;
-
-    // Check if token is expired
-    let now = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
-
-    if token_data.claims.exp < now {
-        return Err(AppError::Unauthorized {
-            message: "Token expired".into(),
-            client_ip,
-            path: req.uri().path().to_string(),
-        });
-    }
-
-    Ok((Session {
-        claims: token_data.claims,
-    }, req))
+/// Cookies that clear the session from the browser, chunks included.
+pub fn removal_cookies(config: &Config, headers: &HeaderMap) -> Vec<Cookie<'static>> {
+    let name = config.session_cookie.full_name();
+    let mut names = sent_session_cookies(config, headers);
+    if !names.contains(&name) {
+        names.insert(0, name);
+    }
+    names.into_iter().map(|name| removal_cookie(config, name)).collect()
 }
 
-pub fn create_session_cookie(token: &str, secure: bool) -> Cookie<'static> {
-    let mut cookie = Cookie::new(SESSION_COOKIE_NAME, token.to_owned());
-    cookie.set_path("/");
-    cookie.set_http_only(true);
-    cookie.set_same_site(Some(cookie::SameSite::Lax));
-    cookie.set_secure(secure);
+fn removal_cookie(config: &Config, name: String) -> Cookie<'static> {
+    let mut cookie = Cookie::new(name, "");
+    // Browsers only drop the cookie with the same domain and path
+    set_scope(config, &mut cookie);
+    cookie.make_removal();
     cookie
 }
 
+fn set_scope(config: &Config, cookie: &mut Cookie<'static>) {
+    cookie.set_path(config.session_cookie.path.clone());
+    if let Some(domain) = &config.session_cookie.domain {
+        cookie.set_domain(domain.clone());
+    }
+    cookie.set_secure(config.is_https());
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    const SESSION_COOKIE_NAME: &str = "authy_session";
+    use axum::body::Body;
+    use crate::{
+        clock::tests::{now, MockClock},
+        config::{
+            CookiePrefix, ProviderConfig, ProviderKind, SameSiteMode, SessionCookieConfig, SessionKey,
+            SessionLifetime, SessionStoreKind,
+        },
+    };
+    use wiremock::{
+        matchers::{body_string_contains, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    async fn create_test_state(cognito_domain: String) -> AppState {
+        AppState::for_tests(Config {
+            providers: vec![ProviderConfig::for_tests(&cognito_domain)],
+            ..Config::for_tests()
+        })
+        .await
+    }
+
+    fn create_test_claims(sub: &str, expires_in: i64) -> Claims {
+        let now = now() as i64;
+        Claims {
+            sub: sub.to_string(),
+            exp: (now + expires_in) as u64,
+            iat: now as u64,
+            nbf: None,
+            iss: Config::for_tests().providers[0].kind.issuer(),
+            aud: None,
+            azp: None,
+            client_id: Some("test-client-id".to_string()),
+            token_use: Some("access".to_string()),
+            groups: Vec::new(),
+            email: None,
+            username: None,
+            extra: Default::default(),
+        }
+    }
+
+    fn encode_claims(claims: &Claims) -> String {
+        crate::verifier::tests::sign(claims)
+    }
+
+    fn create_test_token(sub: &str, expires_in: i64) -> String {
+        encode_claims(&create_test_claims(sub, expires_in))
+    }
+
+    fn create_test_tokens(access_token: String, refresh_token: Option<&str>) -> TokenResponse {
+        TokenResponse {
+            access_token,
+            token_type: "Bearer".to_string(),
+            expires_in: 3600,
+            id_token: None,
+            refresh_token: refresh_token.map(String::from),
+        }
+    }
+
+    /// Put a session straight into the store, bypassing token checks.
+    async fn store_session(state: &AppState, provider: &str, expires_in: i64, refresh_token: Option<&str>) -> Cookie<'static> {
+        let tokens = create_test_tokens(create_test_token("user-1", expires_in), refresh_token);
+        let record = SessionRecord::new(provider, &tokens, create_test_claims("user-1", expires_in), &state.config.session_lifetime, false, now());
+        state.sessions.store().unwrap().save("test-session-id", &record).await.unwrap();
+        Cookie::new(SESSION_COOKIE_NAME, "test-session-id")
+    }
+
+    fn request_with_cookies(cookies: &[Cookie<'_>]) -> Request<Body> {
+        let cookie_header = cookies.iter()
+            .map(|c| format!("{}={}", c.name(), c.value()))
+            .collect::<Vec<_>>()
+            .join("; ");
+        Request::builder()
+            .uri("/protected")
+            .header("x-forwarded-for", "192.168.1.1")
+            .header("cookie", cookie_header)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    async fn mount_refresh(mock_server: &MockServer, access_token: &str) {
+        Mock::given(method("POST"))
+            .and(path("/oauth2/token"))
+            .and(body_string_contains("refresh_token=test-refresh-token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "access_token": access_token,
+                "token_type": "Bearer",
+                "expires_in": 3600
+            })))
+            .mount(mock_server)
+            .await;
+    }
+
     #[tokio::test]
     async fn test_validate_session_no_cookie() {
         let req = Request::builder()
@@ -233,7 +542,8 @@ mod tests {
             .body(Body::empty())
             .unwrap();
 
-        let result = validate_session(req).await;
+        let state = create_test_state("https://test.auth.amazoncognito.com".to_string()).await;
+        let result = validate_session(&state, &req).await;
         assert!(matches!(result,
             Err(AppError::Unauthorized { message, client_ip, path })
             if message == "No session cookie found"
@@ -243,34 +553,519 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_validate_session_invalid_token() {
+    async fn test_validate_session_unknown_id() {
         let req = Request::builder()
             .uri("/protected")
             .header("x-forwarded-for", "192.168.1.1")
-            .header("cookie", "authy_session=invalid.token.here")
+            .header("cookie", "authy_session=forged-session-id")
             .body(Body::empty())
             .unwrap();
 
-        let result = validate_session(req).await;
+        let state = create_test_state("https://test.auth.amazoncognito.com".to_string()).await;
+        let result = validate_session(&state, &req).await;
         assert!(matches!(result,
             Err(AppError::Unauthorized { message, client_ip, path })
-            if message.contains("Invalid token header")
+            if message == "Session expired or not found"
                 && client_ip == "192.168.1.1"
                 && path == "/protected"
         ));
     }
 
-    #[test]
-    fn test_create_session_cookie() {
-        let token = "test.token.here";
-        let cookie = create_session_cookie(token, true);
+    #[tokio::test]
+    async fn test_start_and_validate_session() {
+        let state = create_test_state("https://test.auth.amazoncognito.com".to_string()).await;
+        let token = create_test_token("user-1", 3600);
+        let tokens = create_test_tokens(token.clone(), Some("test-refresh-token"));
+
+        let cookie = start_session(&state, state.default_provider(), &tokens, false, &HeaderMap::new()).await.unwrap().remove(0);
         assert_eq!(cookie.name(), SESSION_COOKIE_NAME);
-        assert_eq!(cookie.value(), token);
-        assert_eq!(cookie.secure(), Some(true));
-        assert_eq!(cookie.http_only(), Some(true));
-        assert_eq!(cookie.same_site(), Some(cookie::SameSite::Lax));
+        // Only the random session ID reaches the browser
+        assert_eq!(cookie.value().len(), 43);
+        assert!(!cookie.value().contains(&token));
+
+        let record = state.sessions.load(cookie.value(), now()).await.unwrap().unwrap();
+        assert_eq!(record.access_token, token);
+        assert_eq!(record.refresh_token.as_deref(), Some("test-refresh-token"));
+
+        let session = validate_session(&state, &request_with_cookies(&[cookie])).await.unwrap();
+        assert_eq!(session.claims.sub, "user-1");
+        assert_eq!(session.provider, "default");
+        assert!(session.cookies.is_empty());
+    }
+
+    async fn create_cookie_state(cognito_domain: String) -> AppState {
+        AppState::for_tests(Config {
+            providers: vec![ProviderConfig::for_tests(&cognito_domain)],
+            session_store: SessionStoreKind::Cookie {
+                keys: vec![SessionKey { id: "k1".to_string(), key: [7; 32] }],
+            },
+            ..Config::for_tests()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_cookie_session() {
+        let state = create_cookie_state("https://test.auth.amazoncognito.com".to_string()).await;
+        let token = create_test_token("user-1", 3600);
+        let tokens = create_test_tokens(token.clone(), Some("test-refresh-token"));
+
+        // The whole record travels in the cookie, sealed
+        let cookie = start_session(&state, state.default_provider(), &tokens, false, &HeaderMap::new()).await.unwrap().remove(0);
+        assert!(cookie.value().starts_with("k1."));
+        assert!(!cookie.value().contains(&token));
+
+        let session = validate_session(&state, &request_with_cookies(std::slice::from_ref(&cookie))).await.unwrap();
+        assert_eq!(session.claims.sub, "user-1");
+        assert!(session.cookies.is_empty());
+
+        let req = request_with_cookies(&[cookie]);
+        let record = end_session(&state, req.headers()).await.unwrap().unwrap();
+        assert_eq!(record.refresh_token.as_deref(), Some("test-refresh-token"));
+
+        // Cookies that do not open are treated like unknown sessions
+        let forged = Cookie::new(SESSION_COOKIE_NAME, "k1.Zm9yZ2Vk");
+        let result = validate_session(&state, &request_with_cookies(&[forged])).await;
+        assert!(matches!(result,
+            Err(AppError::Unauthorized { message, .. }) if message == "Session expired or not found"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_cookie_session_refresh_reissues_cookie() {
+        let mock_server = MockServer::start().await;
+        let new_token = create_test_token("user-1", 3600);
+        mount_refresh(&mock_server, &new_token).await;
+
+        let state = create_cookie_state(mock_server.uri()).await;
+        let Sessions::Cookie(sealer) = &state.sessions else { unreachable!() };
+        let tokens = create_test_tokens(create_test_token("user-1", -3600), Some("test-refresh-token"));
+        let record = SessionRecord::new("default", &tokens, create_test_claims("user-1", -3600), &state.config.session_lifetime, false, now());
+        let cookie = Cookie::new(SESSION_COOKIE_NAME, sealer.seal(&record).unwrap());
 
-        let cookie = create_session_cookie(token, false);
-        assert_eq!(cookie.secure(), Some(false));
+        let session = validate_session(&state, &request_with_cookies(&[cookie])).await.unwrap();
+        assert_eq!(session.cookies.len(), 1);
+        let renewed = sealer.open(session.cookies[0].value(), now()).unwrap();
+        assert_eq!(renewed.access_token, new_token);
+        assert_eq!(renewed.refresh_token.as_deref(), Some("test-refresh-token"));
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_end_session() {
+        let state = create_test_state("https://test.auth.amazoncognito.com".to_string()).await;
+        let cookie = store_session(&state, "default", 3600, Some("test-refresh-token")).await;
+        let req = request_with_cookies(&[cookie]);
+
+        let record = end_session(&state, req.headers()).await.unwrap().unwrap();
+        assert_eq!(record.refresh_token.as_deref(), Some("test-refresh-token"));
+        assert_eq!(state.sessions.load("test-session-id", now()).await.unwrap(), None);
+        assert!(end_session(&state, req.headers()).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_validate_session_unknown_provider() {
+        let state = create_test_state("https://test.auth.amazoncognito.com".to_string()).await;
+        let cookie = store_session(&state, "removed-provider", 3600, None).await;
+
+        let result = validate_session(&state, &request_with_cookies(&[cookie])).await;
+        assert!(matches!(result,
+            Err(AppError::Unauthorized { message, .. }) if message == "Unknown identity provider"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_start_session_id_token() {
+        let state = create_test_state("https://test.auth.amazoncognito.com".to_string()).await;
+        let mut claims = create_test_claims("user-1", 3600);
+        claims.token_use = Some("id".to_string());
+        claims.client_id = None;
+        claims.aud = Some(Audience::One("test-client-id".to_string()));
+        let tokens = create_test_tokens(encode_claims(&claims), None);
+
+        assert!(start_session(&state, state.default_provider(), &tokens, false, &HeaderMap::new()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_start_session_takes_profile_from_id_token() {
+        let state = create_test_state("https://test.auth.amazoncognito.com".to_string()).await;
+        let mut access_claims = create_test_claims("user-1", 3600);
+        access_claims.groups = vec!["admins".to_string()];
+        access_claims.username = Some("jdoe".to_string());
+        let mut id_claims = create_test_claims("user-1", 3600);
+        id_claims.token_use = Some("id".to_string());
+        id_claims.client_id = None;
+        id_claims.aud = Some(Audience::One("test-client-id".to_string()));
+        id_claims.email = Some("jdoe@example.com".to_string());
+        id_claims.groups = vec!["stale".to_string()];
+        id_claims.extra.insert("custom:tenant".to_string(), "acme".into());
+
+        let mut tokens = create_test_tokens(encode_claims(&access_claims), None);
+        tokens.id_token = Some(encode_claims(&id_claims));
+        let cookies = start_session(&state, state.default_provider(), &tokens, false, &HeaderMap::new()).await.unwrap();
+        let session = validate_session(&state, &request_with_cookies(&cookies)).await.unwrap();
+        assert_eq!(session.claims.groups, ["admins"]);
+        assert_eq!(session.claims.username.as_deref(), Some("jdoe"));
+        assert_eq!(session.claims.email.as_deref(), Some("jdoe@example.com"));
+        assert_eq!(session.claims.extra["custom:tenant"], "acme");
+
+        // An ID token for someone else is not mixed in
+        id_claims.sub = "user-2".to_string();
+        tokens.id_token = Some(encode_claims(&id_claims));
+        let result = start_session(&state, state.default_provider(), &tokens, false, &HeaderMap::new()).await;
+        assert!(matches!(result, Err(AppError::Auth(message)) if message.contains("another user")));
+    }
+
+    #[tokio::test]
+    async fn test_start_session_rejects_wrong_claims() {
+        let state = create_test_state("https://test.auth.amazoncognito.com".to_string()).await;
+
+        let mut wrong_client = create_test_claims("user-1", 3600);
+        wrong_client.client_id = Some("other-client".to_string());
+
+        // An ID token's `aud` is not looked at for access tokens
+        let mut aud_on_access_token = create_test_claims("user-1", 3600);
+        aud_on_access_token.client_id = None;
+        aud_on_access_token.aud = Some(Audience::One("test-client-id".to_string()));
+
+        let mut no_token_use = create_test_claims("user-1", 3600);
+        no_token_use.token_use = None;
+
+        let mut wrong_issuer = create_test_claims("user-1", 3600);
+        wrong_issuer.iss = "https://cognito-idp.us-east-1.amazonaws.com/us-east-1_other".to_string();
+
+        let expired = create_test_claims("user-1", -3600);
+
+        for (claims, expected) in [
+            (wrong_client, "issued to another client"),
+            (aud_on_access_token, "issued to another client"),
+            (no_token_use, "unsupported token_use"),
+            (wrong_issuer, "InvalidIssuer"),
+            (expired, "Issued token already expired"),
+        ] {
+            let tokens = create_test_tokens(encode_claims(&claims), None);
+            let result = start_session(&state, state.default_provider(), &tokens, false, &HeaderMap::new()).await;
+            assert!(matches!(&result,
+                Err(AppError::Auth(message)) if message.contains(expected)
+            ), "{:?}", claims);
+        }
+
+        let tokens = create_test_tokens("invalid.token.here".to_string(), None);
+        let result = start_session(&state, state.default_provider(), &tokens, false, &HeaderMap::new()).await;
+        assert!(matches!(result, Err(AppError::Auth(message)) if message.contains("Invalid token header")));
+    }
+
+    #[tokio::test]
+    async fn test_validate_session_refreshes_expired_token() {
+        let mock_server = MockServer::start().await;
+        let new_token = create_test_token("user-1", 3600);
+        mount_refresh(&mock_server, &new_token).await;
+
+        let state = create_test_state(mock_server.uri()).await;
+        let cookie = store_session(&state, "default", -3600, Some("test-refresh-token")).await;
+
+        let session = validate_session(&state, &request_with_cookies(&[cookie])).await.unwrap();
+        assert_eq!(session.claims.sub, "user-1");
+        assert!(session.claims.exp > now() + 3000);
+        // The session ID stays, the tokens behind it are replaced
+        assert_eq!(session.cookies.len(), 1);
+        assert_eq!(session.cookies[0].value(), "test-session-id");
+
+        let record = state.sessions.load("test-session-id", now()).await.unwrap().unwrap();
+        assert_eq!(record.access_token, new_token);
+        assert_eq!(record.refresh_token.as_deref(), Some("test-refresh-token"));
+    }
+
+    #[tokio::test]
+    async fn test_validate_session_refreshes_near_expiry() {
+        let mock_server = MockServer::start().await;
+        let new_token = create_test_token("user-1", 3600);
+        mount_refresh(&mock_server, &new_token).await;
+
+        let state = create_test_state(mock_server.uri()).await;
+        let cookie = store_session(&state, "default", 10, Some("test-refresh-token")).await;
+
+        // The renewed token is the one handed on
+        let session = validate_session(&state, &request_with_cookies(&[cookie])).await.unwrap();
+        assert_eq!(session.access_token, new_token);
+        let record = state.sessions.load("test-session-id", now()).await.unwrap().unwrap();
+        assert_eq!(record.access_token, new_token);
+    }
+
+    #[tokio::test]
+    async fn test_oidc_refresh_without_id_token_keeps_it() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/.well-known/openid-configuration"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "issuer": mock_server.uri(),
+                "authorization_endpoint": format!("{}/authorize", mock_server.uri()),
+                "token_endpoint": format!("{}/token", mock_server.uri()),
+                "jwks_uri": format!("{}/jwks", mock_server.uri())
+            })))
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .and(body_string_contains("refresh_token=test-refresh-token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "access_token": "opaque-access-token",
+                "refresh_token": "test-refresh-token",
+                "token_type": "Bearer",
+                "expires_in": 3600
+            })))
+            .mount(&mock_server)
+            .await;
+        let state = AppState::for_tests(Config {
+            providers: vec![ProviderConfig {
+                kind: ProviderKind::Oidc { issuer: mock_server.uri() },
+                ..ProviderConfig::for_tests(&mock_server.uri())
+            }],
+            ..Config::for_tests()
+        })
+        .await;
+
+        let mut tokens = create_test_tokens("old-access-token".to_string(), Some("test-refresh-token"));
+        tokens.id_token = Some(create_test_token("user-1", 10));
+        let record = SessionRecord::new("default", &tokens, create_test_claims("user-1", 10), &state.config.session_lifetime, false, now());
+        state.sessions.store().unwrap().save("test-session-id", &record).await.unwrap();
+
+        // The opaque access token is not taken for a JWT
+        let cookie = Cookie::new(SESSION_COOKIE_NAME, "test-session-id");
+        let session = validate_session(&state, &request_with_cookies(&[cookie])).await.unwrap();
+        assert_eq!(session.access_token, "opaque-access-token");
+        assert_eq!(session.id_token, tokens.id_token);
+        assert_eq!(session.claims.sub, "user-1");
+        assert!(session.claims.exp > now() + 3000);
+    }
+
+    #[tokio::test]
+    async fn test_validate_session_refresh_failure() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/oauth2/token"))
+            .respond_with(ResponseTemplate::new(400).set_body_string("invalid_grant"))
+            .mount(&mock_server)
+            .await;
+
+        let state = create_test_state(mock_server.uri()).await;
+
+        // The current token is still good for a few seconds
+        let cookie = store_session(&state, "default", 10, Some("test-refresh-token")).await;
+        let session = validate_session(&state, &request_with_cookies(&[cookie])).await.unwrap();
+        assert_eq!(session.claims.sub, "user-1");
+
+        let cookie = store_session(&state, "default", -3600, Some("test-refresh-token")).await;
+        let result = validate_session(&state, &request_with_cookies(&[cookie])).await;
+        assert!(matches!(result,
+            Err(AppError::Unauthorized { message, .. }) if message == "Token expired"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_session_lifetimes() {
+        let mut state = create_test_state("https://test.auth.amazoncognito.com".to_string()).await;
+        state.config.session_lifetime = SessionLifetime {
+            idle_timeout: Some(1800),
+            max_age: 86400,
+            remember_me_max_age: Some(30 * 86400),
+        };
+        let tokens = create_test_tokens(create_test_token("user-1", 3600), Some("test-refresh-token"));
+
+        // The cookie lasts as long as the session may idle
+        let cookie = start_session(&state, state.default_provider(), &tokens, true, &HeaderMap::new())
+            .await
+            .unwrap()
+            .remove(0);
+        assert_eq!(cookie.max_age(), Some(cookie::time::Duration::seconds(1800)));
+        let Sessions::Store(store) = &state.sessions else { unreachable!() };
+        let record = store.load(cookie.value(), now()).await.unwrap().unwrap();
+        assert_eq!(record.max_age, 30 * 86400);
+
+        // Quiet requests leave the session alone...
+        let session = validate_session(&state, &request_with_cookies(std::slice::from_ref(&cookie))).await.unwrap();
+        assert!(session.cookies.is_empty());
+
+        // ...while activity after a while slides the idle timeout
+        let mut record = store.load(cookie.value(), now()).await.unwrap().unwrap();
+        record.last_seen -= 600;
+        record.expires_at -= 600;
+        store.save(cookie.value(), &record).await.unwrap();
+        let session = validate_session(&state, &request_with_cookies(std::slice::from_ref(&cookie))).await.unwrap();
+        assert_eq!(session.cookies.len(), 1);
+        assert_eq!(store.load(cookie.value(), now()).await.unwrap().unwrap().expires_at, now() + 1800);
+
+        // Sessions idle for too long or past their maximum age are gone
+        for (last_seen, created_at) in [(now() - 1801, now() - 1801), (now(), now() - 30 * 86400)] {
+            record.last_seen = last_seen;
+            record.created_at = created_at;
+            // Recomputes the expiry without counting as activity
+            record.update(&tokens, record.claims.clone());
+            store.save(cookie.value(), &record).await.unwrap();
+            let result = validate_session(&state, &request_with_cookies(std::slice::from_ref(&cookie))).await;
+            assert!(matches!(result,
+                Err(AppError::Unauthorized { message, .. }) if message == "Session expired or not found"
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_session_expiry_follows_clock() {
+        let mut state = create_test_state("https://test.auth.amazoncognito.com".to_string()).await;
+        let clock = std::sync::Arc::new(MockClock::new());
+        state.clock = clock.clone();
+        state.config.session_lifetime.idle_timeout = Some(1800);
+        let cookie = store_session(&state, "default", 3600, Some("test-refresh-token")).await;
+
+        // Each request pushes the idle timeout back...
+        for _ in 0..3 {
+            clock.advance(1000);
+            let session = validate_session(&state, &request_with_cookies(std::slice::from_ref(&cookie))).await.unwrap();
+            assert_eq!(session.cookies.len(), 1);
+            assert_eq!(session.cookies[0].max_age(), Some(cookie::time::Duration::seconds(1800)));
+        }
+
+        // ...until the user goes quiet for too long
+        clock.advance(1801);
+        let result = validate_session(&state, &request_with_cookies(&[cookie])).await;
+        assert!(matches!(result,
+            Err(AppError::Unauthorized { message, .. }) if message == "Session expired or not found"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_validate_session_token_leeway() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/oauth2/token"))
+            .respond_with(ResponseTemplate::new(400).set_body_string("invalid_grant"))
+            .mount(&mock_server)
+            .await;
+
+        let mut state = create_test_state(mock_server.uri()).await;
+        let clock = std::sync::Arc::new(MockClock::new());
+        state.clock = clock.clone();
+
+        // Just past expiry by our clock is within the provider's skew
+        let cookie = store_session(&state, "default", -30, Some("test-refresh-token")).await;
+        validate_session(&state, &request_with_cookies(std::slice::from_ref(&cookie))).await.unwrap();
+
+        clock.advance(31);
+        let result = validate_session(&state, &request_with_cookies(&[cookie])).await;
+        assert!(matches!(result,
+            Err(AppError::Unauthorized { message, .. }) if message == "Token expired"
+        ));
+    }
+
+    #[test]
+    fn test_create_session_cookies() {
+        let https = Config {
+            server_domain: "https://auth.example.com".to_string(),
+            ..Config::for_tests()
+        };
+        let cookies = create_session_cookies(&https, "session-id", now() + 3600, now(), &HeaderMap::new()).unwrap();
+        assert_eq!(cookies.len(), 1);
+        assert_eq!(cookies[0].name(), SESSION_COOKIE_NAME);
+        assert_eq!(cookies[0].value(), "session-id");
+        assert_eq!(cookies[0].secure(), Some(true));
+        assert_eq!(cookies[0].http_only(), Some(true));
+        assert_eq!(cookies[0].same_site(), Some(cookie::SameSite::Lax));
+        assert!(cookies[0].max_age().unwrap() >= cookie::time::Duration::seconds(3599));
+
+        let cookies = create_session_cookies(&Config::for_tests(), "session-id", now() + 3600, now(), &HeaderMap::new()).unwrap();
+        assert_eq!(cookies[0].secure(), Some(false));
+    }
+
+    #[test]
+    fn test_configured_cookie_attributes() {
+        let config = Config {
+            server_domain: "https://auth.example.com".to_string(),
+            session_cookie: SessionCookieConfig {
+                name: "app_session".to_string(),
+                domain: Some("example.com".to_string()),
+                path: "/app".to_string(),
+                same_site: SameSiteMode::Strict,
+                prefix: Some(CookiePrefix::Secure),
+            },
+            ..Config::for_tests()
+        };
+
+        let cookies = create_session_cookies(&config, "session-id", now() + 3600, now(), &HeaderMap::new()).unwrap();
+        assert_eq!(cookies[0].name(), "__Secure-app_session");
+        assert_eq!(cookies[0].domain(), Some("example.com"));
+        assert_eq!(cookies[0].path(), Some("/app"));
+        assert_eq!(cookies[0].same_site(), Some(cookie::SameSite::Strict));
+        assert_eq!(cookies[0].secure(), Some(true));
+
+        // Read back under the configured name only
+        let headers = headers_with_cookies(&cookies);
+        assert_eq!(session_cookie_value(&config, &headers).as_deref(), Some("session-id"));
+        assert_eq!(session_cookie_value(&Config::for_tests(), &headers), None);
+
+        // Removals carry the same scope, or browsers would keep the cookie
+        let removal = removal_cookies(&config, &headers).remove(0);
+        assert_eq!(removal.name(), "__Secure-app_session");
+        assert_eq!(removal.domain(), Some("example.com"));
+        assert_eq!(removal.path(), Some("/app"));
+    }
+
+    fn headers_with_cookies(cookies: &[Cookie<'_>]) -> HeaderMap {
+        request_with_cookies(cookies).headers().clone()
+    }
+
+    #[test]
+    fn test_large_session_cookies_are_chunked() {
+        let config = Config::for_tests();
+        let value = "a".repeat(MAX_COOKIE_VALUE_LEN * 2) + "tail";
+        let cookies = create_session_cookies(&config, &value, now() + 3600, now(), &HeaderMap::new()).unwrap();
+        let names: Vec<_> = cookies.iter().map(|c| c.name()).collect();
+        assert_eq!(names, ["authy_session.0", "authy_session.1", "authy_session.2"]);
+        assert!(cookies.iter().all(|c| c.value().len() <= MAX_COOKIE_VALUE_LEN && c.http_only() == Some(true)));
+
+        // Reassembled in order on the way back in
+        let headers = headers_with_cookies(&cookies);
+        assert_eq!(session_cookie_value(&config, &headers), Some(value));
+
+        // Shrinking clears the chunks the new value does not overwrite
+        let cookies = create_session_cookies(&config, "small", now() + 3600, now(), &headers).unwrap();
+        let cleared: Vec<_> = cookies.iter().filter(|c| c.value().is_empty()).map(|c| c.name()).collect();
+        assert_eq!(cookies[0].name(), SESSION_COOKIE_NAME);
+        assert_eq!(cleared.len(), 3);
+
+        let cookies = create_session_cookies(&config, &"b".repeat(MAX_COOKIE_VALUE_LEN + 1), now() + 3600, now(), &headers).unwrap();
+        let cleared: Vec<_> = cookies.iter().filter(|c| c.value().is_empty()).map(|c| c.name()).collect();
+        assert_eq!(cookies.len(), 3);
+        assert_eq!(cleared, ["authy_session.2"]);
+
+        // No more chunks are written than are read back
+        let largest = "c".repeat(MAX_COOKIE_VALUE_LEN * MAX_COOKIE_CHUNKS);
+        let cookies = create_session_cookies(&config, &largest, now() + 3600, now(), &HeaderMap::new()).unwrap();
+        assert_eq!(cookies.len(), MAX_COOKIE_CHUNKS);
+        assert_eq!(session_cookie_value(&config, &headers_with_cookies(&cookies)), Some(largest.clone()));
+        let result = create_session_cookies(&config, &(largest + "c"), now() + 3600, now(), &HeaderMap::new());
+        assert!(matches!(result, Err(AppError::Internal(message)) if message.contains("does not fit")));
+
+        // Ending the session clears every chunk
+        let names: Vec<_> = removal_cookies(&config, &headers).iter().map(|c| c.name().to_owned()).collect();
+        assert_eq!(names, ["authy_session", "authy_session.0", "authy_session.1", "authy_session.2"]);
+    }
+
+    #[tokio::test]
+    async fn test_cookie_session_with_large_tokens() {
+        let state = create_cookie_state("https://test.auth.amazoncognito.com".to_string()).await;
+        let mut tokens = create_test_tokens(create_test_token("user-1", 3600), Some("test-refresh-token"));
+        // An ID token carrying a long list of groups
+        let mut id_claims = create_test_claims("user-1", 3600);
+        id_claims.token_use = Some("id".to_string());
+        id_claims.client_id = None;
+        id_claims.aud = Some(Audience::One("test-client-id".to_string()));
+        id_claims.groups = (0..300).map(|i| format!("department-{:04}", i)).collect();
+        tokens.id_token = Some(encode_claims(&id_claims));
+
+        let cookies = start_session(&state, state.default_provider(), &tokens, false, &HeaderMap::new()).await.unwrap();
+        assert!(cookies.len() > 1);
+
+        let session = validate_session(&state, &request_with_cookies(&cookies)).await.unwrap();
+        assert_eq!(session.claims.sub, "user-1");
+        assert_eq!(session.claims.groups.len(), 300);
+    }
+}
@@ -0,0 +1,145 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+
+use crate::{
+    config::SessionKey,
+    error::AppError,
+    store::SessionRecord,
+};
+
+/// Seals session records into cookie values the browser can neither read
+/// nor alter.
+///
+/// A sealed value is `<key id>.<base64url(nonce || ciphertext)>`. The key ID
+/// is bound to the ciphertext as associated data, so it cannot be swapped
+/// to make a cookie look like it came from another key.
+#[derive(Clone)]
+pub struct SessionSealer {
+    /// The first key seals, every key opens
+    keys: Vec<(String, XChaCha20Poly1305)>,
+}
+
+impl SessionSealer {
+    pub fn new(keys: &[SessionKey]) -> Self {
+        assert!(!keys.is_empty(), "cookie sessions need at least one key");
+        SessionSealer {
+            keys: keys
+                .iter()
+                .map(|key| (key.id.clone(), XChaCha20Poly1305::new(&key.key.into())))
+                .collect(),
+        }
+    }
+
+    pub fn seal(&self, record: &SessionRecord) -> Result<String, AppError> {
+        let (id, cipher) = &self.keys[0];
+        let plaintext = serde_json::to_vec(record)
+            .map_err(|e| AppError::Internal(format!("Failed to serialize session: {}", e)))?;
+
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(&nonce, Payload { msg: &plaintext, aad: id.as_bytes() })
+            .map_err(|_| AppError::Internal("Failed to encrypt session".into()))?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        Ok(format!("{}.{}", id, URL_SAFE_NO_PAD.encode(sealed)))
+    }
+
+    /// The record sealed into `value`, unless it was sealed with a retired
+    /// key, tampered with or has expired at `now`.
+    pub fn open(&self, value: &str, now: u64) -> Option<SessionRecord> {
+        let (id, sealed) = value.split_once('.')?;
+        let (_, cipher) = self.keys.iter().find(|(key_id, _)| key_id == id)?;
+
+        let sealed = URL_SAFE_NO_PAD.decode(sealed).ok()?;
+        if sealed.len() < 24 {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(24);
+        let plaintext = cipher
+            .decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad: id.as_bytes() })
+            .ok()?;
+
+        serde_json::from_slice::<SessionRecord>(&plaintext)
+            .ok()
+            .filter(|record| record.expires_at > now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::{tests::{now, MockClock}, Clock},
+        store::tests::create_test_record,
+    };
+
+    fn key(id: &str, byte: u8) -> SessionKey {
+        SessionKey { id: id.to_string(), key: [byte; 32] }
+    }
+
+    #[test]
+    fn test_seal_and_open() {
+        let sealer = SessionSealer::new(&[key("k1", 1)]);
+        let record = create_test_record(now() + 3600);
+
+        let sealed = sealer.seal(&record).unwrap();
+        assert!(sealed.starts_with("k1."));
+        assert!(!sealed.contains("test-access-token"));
+        assert_eq!(sealer.open(&sealed, now()), Some(record.clone()));
+
+        // Fresh nonce every time
+        assert_ne!(sealer.seal(&record).unwrap(), sealed);
+    }
+
+    #[test]
+    fn test_open_rejects_bad_cookies() {
+        let sealer = SessionSealer::new(&[key("k1", 1)]);
+        let sealed = sealer.seal(&create_test_record(now() + 3600)).unwrap();
+
+        // Tampered ciphertext
+        let mut tampered = sealed.clone().into_bytes();
+        let last = tampered.len() - 1;
+        tampered[last] = if tampered[last] == b'A' { b'B' } else { b'A' };
+        assert_eq!(sealer.open(&String::from_utf8(tampered).unwrap(), now()), None);
+
+        // Relabelled with another key ID
+        let other = SessionSealer::new(&[key("k2", 1)]);
+        assert_eq!(other.open(&sealed.replacen("k1.", "k2.", 1), now()), None);
+
+        // Garbage and expired records
+        assert_eq!(sealer.open("not-a-session", now()), None);
+        assert_eq!(sealer.open("k1.AAAA", now()), None);
+        assert_eq!(sealer.open(&sealer.seal(&create_test_record(now() - 1)).unwrap(), now()), None);
+    }
+
+    #[test]
+    fn test_expiry_follows_clock() {
+        let clock = MockClock::new();
+        let sealer = SessionSealer::new(&[key("k1", 1)]);
+        let sealed = sealer.seal(&create_test_record(clock.now() + 60)).unwrap();
+        assert!(sealer.open(&sealed, clock.now()).is_some());
+
+        clock.advance(61);
+        assert_eq!(sealer.open(&sealed, clock.now()), None);
+    }
+
+    #[test]
+    fn test_key_rotation() {
+        let record = create_test_record(now() + 3600);
+        let old = SessionSealer::new(&[key("old", 1)]);
+        let sealed_with_old = old.seal(&record).unwrap();
+
+        // A new primary key still opens cookies sealed with the old one...
+        let rotated = SessionSealer::new(&[key("new", 2), key("old", 1)]);
+        assert_eq!(rotated.open(&sealed_with_old, now()), Some(record.clone()));
+        assert!(rotated.seal(&record).unwrap().starts_with("new."));
+
+        // ...until the old key is retired
+        let retired = SessionSealer::new(&[key("new", 2)]);
+        assert_eq!(retired.open(&sealed_with_old, now()), None);
+    }
+}
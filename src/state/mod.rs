@@ -0,0 +1,117 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::extract::FromRef;
+
+use crate::{
+    assertion::AssertionSigner,
+    auth::refresh::TokenRefresher,
+    clock::{Clock, SystemClock},
+    config::{Config, SessionStoreKind},
+    error::AppError,
+    jwks::TrustAnchors,
+    provider::Provider,
+    session::{Claims, SessionSealer, Sessions},
+    store::{MemoryStore, SqliteStore},
+    verifier::{JwksVerifier, TokenError, TokenVerifier},
+};
+
+/// Shared application state handed to every handler.
+#[derive(Clone)]
+pub struct AppState {
+    pub config: Config,
+    /// In configuration order, never empty
+    pub providers: Vec<Provider>,
+    pub refresher: TokenRefresher,
+    pub sessions: Sessions,
+    pub verifier: Arc<dyn TokenVerifier>,
+    pub clock: Arc<dyn Clock>,
+    /// Signs identity assertions, when a key is configured
+    pub assertions: Option<Arc<AssertionSigner>>,
+}
+
+impl AppState {
+    /// Build the state, running OpenID discovery for every configured provider.
+    pub async fn new(config: Config) -> Result<Self, AppError> {
+        let x5c_anchors = match &config.jwks_x5c_ca_file {
+            Some(path) if config.jwks_validate_x5c => {
+                let bundle = std::fs::read_to_string(path)
+                    .map_err(|e| AppError::Internal(format!("Failed to read x5c CA bundle {}: {}", path, e)))?;
+                let anchors = TrustAnchors::from_pem(&bundle)
+                    .map_err(|e| AppError::Internal(format!("x5c CA bundle {}: {}", path, e)))?;
+                Some(Arc::new(anchors))
+            }
+            _ => None,
+        };
+
+        let refresh_interval = Duration::from_secs(config.jwks_refresh_interval);
+        let mut providers = Vec::with_capacity(config.providers.len());
+        for provider in &config.providers {
+            let mut provider = Provider::new(provider.clone(), refresh_interval).await?;
+            if let Some(anchors) = &x5c_anchors {
+                provider.jwks = provider.jwks.validating_x5c(anchors.clone());
+            }
+            providers.push(provider);
+        }
+        if providers.is_empty() {
+            return Err(AppError::Internal("No identity providers configured".into()));
+        }
+
+        let sessions = match &config.session_store {
+            SessionStoreKind::Memory => Sessions::Store(Arc::new(MemoryStore::new())),
+            SessionStoreKind::Sqlite { path } => Sessions::Store(Arc::new(SqliteStore::open(path)?)),
+            SessionStoreKind::Cookie { keys } => Sessions::Cookie(SessionSealer::new(keys)),
+        };
+
+        let assertions = match &config.assertion_key_file {
+            Some(path) => {
+                let pem = std::fs::read_to_string(path)
+                    .map_err(|e| AppError::Internal(format!("Failed to read assertion key {}: {}", path, e)))?;
+                let issuer = config.server_domain.trim_end_matches('/').to_string();
+                let audience = config.assertion_audience.clone().unwrap_or_else(|| config.protected_website_url.clone());
+                let signer = AssertionSigner::from_pem(&pem, issuer, audience, config.assertion_lifetime)
+                    .map_err(|e| AppError::Internal(format!("Assertion key {}: {}", path, e)))?;
+                Some(Arc::new(signer))
+            }
+            None => None,
+        };
+
+        Ok(AppState {
+            config,
+            providers,
+            refresher: TokenRefresher::new(),
+            sessions,
+            verifier: Arc::new(JwksVerifier),
+            clock: Arc::new(SystemClock),
+            assertions,
+        })
+    }
+
+    /// State for unit tests, trusting tokens signed with the test key.
+    #[cfg(test)]
+    pub async fn for_tests(config: Config) -> Self {
+        AppState {
+            verifier: Arc::new(crate::verifier::tests::TestVerifier),
+            ..Self::new(config).await.unwrap()
+        }
+    }
+
+    /// Verify a provider's token against our clock and configured leeway.
+    pub async fn verify(&self, provider: &Provider, token: &str) -> Result<Claims, TokenError> {
+        self.verifier.verify(provider, token, self.clock.now(), self.config.token_leeway).await
+    }
+
+    pub fn provider(&self, name: &str) -> Option<&Provider> {
+        self.providers.iter().find(|provider| provider.name() == name)
+    }
+
+    /// The provider sessions without a recorded provider belong to.
+    pub fn default_provider(&self) -> &Provider {
+        &self.providers[0]
+    }
+}
+
+impl FromRef<AppState> for Config {
+    fn from_ref(state: &AppState) -> Config {
+        state.config.clone()
+    }
+}
@@ -0,0 +1,55 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use async_trait::async_trait;
+
+use crate::error::AppError;
+
+use super::{SessionRecord, SessionStore};
+
+/// Sessions held in process memory, lost on restart and not shared
+/// between instances.
+#[derive(Default)]
+pub struct MemoryStore {
+    sessions: Mutex<HashMap<String, SessionRecord>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl SessionStore for MemoryStore {
+    async fn load(&self, id: &str, now: u64) -> Result<Option<SessionRecord>, AppError> {
+        let sessions = self.sessions.lock().unwrap();
+        Ok(sessions.get(id).filter(|record| record.expires_at > now).cloned())
+    }
+
+    async fn save(&self, id: &str, record: &SessionRecord) -> Result<(), AppError> {
+        self.sessions.lock().unwrap().insert(id.to_owned(), record.clone());
+        Ok(())
+    }
+
+    async fn delete(&self, id: &str) -> Result<(), AppError> {
+        self.sessions.lock().unwrap().remove(id);
+        Ok(())
+    }
+
+    async fn purge_expired(&self, now: u64) -> Result<usize, AppError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let before = sessions.len();
+        sessions.retain(|_, record| record.expires_at > now);
+        Ok(before - sessions.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_memory_store() {
+        super::super::tests::exercise_store(&MemoryStore::new()).await;
+    }
+}
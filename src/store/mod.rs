@@ -0,0 +1,262 @@
+pub mod memory;
+pub mod sqlite;
+
+use std::{
+    sync::Arc,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::{auth::TokenResponse, clock::Clock, config::SessionLifetime, error::AppError, session::Claims};
+
+pub use self::{memory::MemoryStore, sqlite::SqliteStore};
+
+/// How often expired sessions are swept out of the store.
+const PURGE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Everything authy knows about a signed-in user, kept server-side.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct SessionRecord {
+    /// Name of the provider that issued the tokens
+    pub provider: String,
+    /// Claims of the verified session token
+    pub claims: Claims,
+    pub access_token: String,
+    pub id_token: Option<String>,
+    pub refresh_token: Option<String>,
+    /// Unix time the user signed in
+    #[serde(default)]
+    pub created_at: u64,
+    /// Unix time the session was last used, as far as the idle timeout cares
+    #[serde(default)]
+    pub last_seen: u64,
+    /// Absolute lifetime, fixed at sign-in
+    #[serde(default)]
+    pub max_age: u64,
+    #[serde(default)]
+    pub idle_timeout: Option<u64>,
+    /// Unix time after which the record is dead and may be purged
+    pub expires_at: u64,
+}
+
+impl SessionRecord {
+    pub fn new(
+        provider: &str,
+        tokens: &TokenResponse,
+        claims: Claims,
+        lifetime: &SessionLifetime,
+        remember: bool,
+        now: u64,
+    ) -> Self {
+        let mut record = SessionRecord {
+            provider: provider.to_string(),
+            claims,
+            access_token: tokens.access_token.clone(),
+            id_token: tokens.id_token.clone(),
+            refresh_token: tokens.refresh_token.clone(),
+            created_at: now,
+            last_seen: now,
+            max_age: lifetime.max_age(remember),
+            idle_timeout: lifetime.idle_timeout,
+            expires_at: 0,
+        };
+        record.expires_at = record.lifetime_end();
+        record
+    }
+
+    /// Take over the tokens from a refresh, keeping what it did not resend.
+    pub fn update(&mut self, tokens: &TokenResponse, claims: Claims) {
+        self.claims = claims;
+        self.access_token = tokens.access_token.clone();
+        if let Some(id_token) = &tokens.id_token {
+            self.id_token = Some(id_token.clone());
+        }
+        if let Some(refresh_token) = &tokens.refresh_token {
+            self.refresh_token = Some(refresh_token.clone());
+        }
+        self.expires_at = self.lifetime_end();
+    }
+
+    /// Note activity at `now`, pushing back the idle timeout.
+    pub fn touch(&mut self, now: u64) {
+        self.last_seen = now;
+        self.expires_at = self.lifetime_end();
+    }
+
+    fn lifetime_end(&self) -> u64 {
+        let mut end = self.created_at + self.max_age;
+        // Without a refresh token the session cannot outlive its token
+        if self.refresh_token.is_none() {
+            end = end.min(self.claims.exp);
+        }
+        if let Some(idle_timeout) = self.idle_timeout {
+            end = end.min(self.last_seen + idle_timeout);
+        }
+        end
+    }
+}
+
+/// Where sessions live between requests, keyed by the opaque ID in the
+/// session cookie.
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    /// The session with this ID, unless it is unknown or expired at `now`.
+    async fn load(&self, id: &str, now: u64) -> Result<Option<SessionRecord>, AppError>;
+
+    async fn save(&self, id: &str, record: &SessionRecord) -> Result<(), AppError>;
+
+    async fn delete(&self, id: &str) -> Result<(), AppError>;
+
+    /// Drop every session that expired before `now`, returning how many.
+    async fn purge_expired(&self, now: u64) -> Result<usize, AppError>;
+}
+
+/// Sweep expired sessions out of the store for the life of the process.
+pub fn spawn_purge_task(store: Arc<dyn SessionStore>, clock: Arc<dyn Clock>) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PURGE_INTERVAL);
+        loop {
+            interval.tick().await;
+            match store.purge_expired(clock.now()).await {
+                Ok(0) => {}
+                Ok(purged) => tracing::debug!("Purged {} expired sessions", purged),
+                Err(e) => tracing::warn!("Failed to purge expired sessions: {}", e),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::clock::tests::{now, MockClock};
+
+    pub fn create_test_record(expires_at: u64) -> SessionRecord {
+        SessionRecord {
+            provider: "default".to_string(),
+            claims: Claims {
+                sub: "user-1".to_string(),
+                exp: expires_at,
+                iat: 0,
+                nbf: None,
+                iss: "https://issuer.example.com".to_string(),
+                aud: None,
+                azp: None,
+                client_id: Some("test-client-id".to_string()),
+                token_use: Some("access".to_string()),
+                groups: Vec::new(),
+                email: None,
+                username: None,
+                extra: Default::default(),
+            },
+            access_token: "test-access-token".to_string(),
+            id_token: None,
+            refresh_token: None,
+            created_at: now(),
+            last_seen: now(),
+            max_age: 30 * 24 * 60 * 60,
+            idle_timeout: None,
+            expires_at,
+        }
+    }
+
+    /// Behaviour every store must share.
+    pub async fn exercise_store(store: &dyn SessionStore) {
+        let live = create_test_record(now() + 3600);
+        let dead = create_test_record(now() - 1);
+
+        store.save("live", &live).await.unwrap();
+        store.save("dead", &dead).await.unwrap();
+        assert_eq!(store.load("live", now()).await.unwrap(), Some(live.clone()));
+        assert_eq!(store.load("dead", now()).await.unwrap(), None);
+        assert_eq!(store.load("unknown", now()).await.unwrap(), None);
+
+        // Saving again replaces the record
+        let mut renewed = live.clone();
+        renewed.access_token = "renewed-access-token".to_string();
+        store.save("live", &renewed).await.unwrap();
+        assert_eq!(store.load("live", now()).await.unwrap(), Some(renewed));
+
+        assert_eq!(store.purge_expired(now()).await.unwrap(), 1);
+        assert_eq!(store.purge_expired(now()).await.unwrap(), 0);
+
+        store.delete("live").await.unwrap();
+        assert_eq!(store.load("live", now()).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_expiry_follows_clock() {
+        let stores: [Arc<dyn SessionStore>; 2] = [Arc::new(MemoryStore::new()), Arc::new(SqliteStore::open_in_memory().unwrap())];
+        for store in stores {
+            let clock = MockClock::new();
+            store.save("session", &create_test_record(clock.now() + 60)).await.unwrap();
+            assert!(store.load("session", clock.now()).await.unwrap().is_some());
+
+            clock.advance(61);
+            assert_eq!(store.load("session", clock.now()).await.unwrap(), None);
+            assert_eq!(store.purge_expired(clock.now()).await.unwrap(), 1);
+        }
+    }
+
+    #[test]
+    fn test_record_lifetime() {
+        let tokens = TokenResponse {
+            access_token: "access".to_string(),
+            token_type: "Bearer".to_string(),
+            expires_in: 3600,
+            id_token: Some("id".to_string()),
+            refresh_token: None,
+        };
+        let claims = create_test_record(now() + 3600).claims;
+        let lifetime = SessionLifetime {
+            idle_timeout: None,
+            max_age: 86400,
+            remember_me_max_age: Some(7 * 86400),
+        };
+
+        // Bounded by the token without a refresh token...
+        let mut record = SessionRecord::new("default", &tokens, claims.clone(), &lifetime, false, now());
+        assert_eq!(record.expires_at, claims.exp);
+
+        // ...and by the maximum age with one, which a refresh response
+        // without a new refresh token must not lose
+        record.refresh_token = Some("refresh".to_string());
+        record.update(&TokenResponse { id_token: None, ..tokens.clone() }, claims.clone());
+        assert_eq!(record.refresh_token.as_deref(), Some("refresh"));
+        assert_eq!(record.id_token.as_deref(), Some("id"));
+        assert_eq!(record.expires_at, record.created_at + 86400);
+
+        // Remember me stretches the maximum age
+        let tokens = TokenResponse { refresh_token: Some("refresh".to_string()), ..tokens };
+        let record = SessionRecord::new("default", &tokens, claims.clone(), &lifetime, true, now());
+        assert_eq!(record.expires_at, record.created_at + 7 * 86400);
+    }
+
+    #[test]
+    fn test_record_idle_timeout() {
+        let tokens = TokenResponse {
+            access_token: "access".to_string(),
+            token_type: "Bearer".to_string(),
+            expires_in: 3600,
+            id_token: None,
+            refresh_token: Some("refresh".to_string()),
+        };
+        let claims = create_test_record(now() + 3600).claims;
+        let lifetime = SessionLifetime {
+            idle_timeout: Some(1800),
+            max_age: 86400,
+            remember_me_max_age: None,
+        };
+
+        let mut record = SessionRecord::new("default", &tokens, claims, &lifetime, true, now());
+        assert_eq!(record.expires_at, record.last_seen + 1800);
+
+        // Activity slides the deadline, but never past the maximum age
+        record.last_seen -= 600;
+        record.created_at -= 86400 - 900;
+        record.touch(now());
+        assert_eq!(record.expires_at, record.created_at + 86400);
+    }
+}
@@ -0,0 +1,170 @@
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::error::AppError;
+
+use super::{SessionRecord, SessionStore};
+
+/// Sessions persisted to a SQLite database, surviving restarts.
+///
+/// Records are stored as JSON next to their expiry so purging does not
+/// need to parse them.
+#[derive(Clone)]
+pub struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    pub fn open(path: &str) -> Result<Self, AppError> {
+        let conn = Connection::open(path).map_err(store_error)?;
+        Self::init(conn)
+    }
+
+    #[cfg(test)]
+    pub fn open_in_memory() -> Result<Self, AppError> {
+        Self::init(Connection::open_in_memory().map_err(store_error)?)
+    }
+
+    fn init(conn: Connection) -> Result<Self, AppError> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS sessions (
+                id TEXT PRIMARY KEY,
+                record TEXT NOT NULL,
+                expires_at INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS sessions_expires_at ON sessions (expires_at);",
+        )
+        .map_err(store_error)?;
+
+        Ok(SqliteStore {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Run a query on the blocking pool, SQLite calls must not stall the runtime.
+    async fn with_conn<T, F>(&self, f: F) -> Result<T, AppError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || f(&conn.lock().unwrap()))
+            .await
+            .map_err(|e| AppError::Internal(format!("Session store task failed: {}", e)))?
+            .map_err(store_error)
+    }
+}
+
+#[async_trait]
+impl SessionStore for SqliteStore {
+    async fn load(&self, id: &str, now: u64) -> Result<Option<SessionRecord>, AppError> {
+        let owned_id = id.to_owned();
+        let json = self
+            .with_conn(move |conn| {
+                conn.query_row(
+                    "SELECT record FROM sessions WHERE id = ?1 AND expires_at > ?2",
+                    params![owned_id, now],
+                    |row| row.get::<_, String>(0),
+                )
+                .optional()
+            })
+            .await?;
+        let Some(json) = json else {
+            return Ok(None);
+        };
+
+        match serde_json::from_str(&json) {
+            Ok(record) => Ok(Some(record)),
+            Err(e) => {
+                // A record that will not decode is as good as no session,
+                // failing every request until it expires helps nobody
+                tracing::warn!("Dropping undecodable session record: {}", e);
+                self.delete(id).await?;
+                Ok(None)
+            }
+        }
+    }
+
+    async fn save(&self, id: &str, record: &SessionRecord) -> Result<(), AppError> {
+        let id = id.to_owned();
+        let expires_at = record.expires_at;
+        let json = serde_json::to_string(record)
+            .map_err(|e| AppError::Internal(format!("Failed to serialize session: {}", e)))?;
+
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO sessions (id, record, expires_at) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (id) DO UPDATE SET record = excluded.record, expires_at = excluded.expires_at",
+                params![id, json, expires_at],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn delete(&self, id: &str) -> Result<(), AppError> {
+        let id = id.to_owned();
+        self.with_conn(move |conn| conn.execute("DELETE FROM sessions WHERE id = ?1", params![id]))
+            .await?;
+        Ok(())
+    }
+
+    async fn purge_expired(&self, now: u64) -> Result<usize, AppError> {
+        self.with_conn(move |conn| {
+            conn.execute("DELETE FROM sessions WHERE expires_at <= ?1", params![now])
+        })
+        .await
+    }
+}
+
+fn store_error(e: rusqlite::Error) -> AppError {
+    AppError::Internal(format!("Session store error: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::tests::now;
+
+    #[tokio::test]
+    async fn test_sqlite_store() {
+        super::super::tests::exercise_store(&SqliteStore::open_in_memory().unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_store_persists() {
+        let path = std::env::temp_dir().join(format!("authy-sessions-{}.db", std::process::id()));
+        let path = path.to_str().unwrap();
+        let record = super::super::tests::create_test_record(now() + 3600);
+
+        SqliteStore::open(path).unwrap().save("session-id", &record).await.unwrap();
+        let reopened = SqliteStore::open(path).unwrap();
+        assert_eq!(reopened.load("session-id", now()).await.unwrap(), Some(record));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_store_drops_corrupt_records() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let expires_at = now() + 3600;
+        store
+            .with_conn(move |conn| {
+                conn.execute(
+                    "INSERT INTO sessions (id, record, expires_at) VALUES ('corrupt', '{\"sub\":', ?1)",
+                    params![expires_at],
+                )
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(store.load("corrupt", now()).await.unwrap(), None);
+        let remaining = store
+            .with_conn(|conn| conn.query_row("SELECT COUNT(*) FROM sessions", [], |row| row.get::<_, i64>(0)))
+            .await
+            .unwrap();
+        assert_eq!(remaining, 0);
+    }
+}
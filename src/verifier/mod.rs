@@ -0,0 +1,504 @@
+use async_trait::async_trait;
+use jsonwebtoken::{
+    decode, decode_header,
+    errors::ErrorKind,
+    jwk::{AlgorithmParameters, EllipticCurve, Jwk, KeyAlgorithm, PublicKeyUse},
+    Algorithm, DecodingKey, Validation,
+};
+
+use crate::{provider::Provider, session::Claims};
+
+pub enum TokenError {
+    Expired,
+    Invalid(String),
+}
+
+const RSA_ALGORITHMS: [Algorithm; 6] = [
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+];
+
+/// A provider's public key and the algorithms it may be used with.
+pub struct VerificationKey {
+    pub key: DecodingKey,
+    pub algorithms: Vec<Algorithm>,
+}
+
+impl VerificationKey {
+    /// Build a key from a JWK, honouring its `alg` and `use` when present.
+    pub fn from_jwk(jwk: &Jwk) -> Result<Self, TokenError> {
+        let algorithms = key_algorithms(jwk).map_err(TokenError::Invalid)?;
+        let key = DecodingKey::from_jwk(jwk)
+            .map_err(|e| TokenError::Invalid(format!("Invalid key components: {}", e)))?;
+        Ok(VerificationKey { key, algorithms })
+    }
+}
+
+/// Checks that a token was issued to us by a provider and is still valid.
+///
+/// Implementations only decide where signing keys come from. The signature
+/// and claim checks in `verify` are shared, so tests run the same path as
+/// production.
+#[async_trait]
+pub trait TokenVerifier: Send + Sync {
+    /// The key the provider signs tokens carrying this key ID with.
+    async fn key(&self, provider: &Provider, kid: &str) -> Result<VerificationKey, TokenError>;
+
+    /// Verify a token at Unix time `now`, allowing `leeway` seconds of clock
+    /// skew on its time claims.
+    async fn verify(&self, provider: &Provider, token: &str, now: u64, leeway: u64) -> Result<Claims, TokenError> {
+        // Get the key ID from the token header
+        let header = decode_header(token)
+            .map_err(|e| TokenError::Invalid(format!("Invalid token header: {}", e)))?;
+        let kid = header.kid
+            .ok_or_else(|| TokenError::Invalid("No key ID in token".into()))?;
+        let key = self.key(provider, &kid).await?;
+
+        // The header names the algorithm, but only the key decides which are
+        // acceptable, so a token cannot talk us into e.g. HMAC with a public key
+        if !key.algorithms.contains(&header.alg) {
+            return Err(TokenError::Invalid(format!(
+                "Invalid token: algorithm {:?} does not match the key",
+                header.alg
+            )));
+        }
+
+        // Validate the token. The audience lives in a different claim depending
+        // on the provider and token type, and times are judged by our clock,
+        // so both are checked separately below.
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&provider.metadata.issuer]);
+        validation.validate_aud = false;
+        validation.validate_exp = false;
+
+        let claims = decode::<Claims>(token, &key.key, &validation)
+            .map(|data| data.claims)
+            .map_err(token_error)?;
+
+        check_times(&claims, now, leeway)?;
+        check_audience(provider, &claims)?;
+        Ok(claims)
+    }
+}
+
+/// Verifies tokens against the keys the provider publishes in its JWKS.
+pub struct JwksVerifier;
+
+#[async_trait]
+impl TokenVerifier for JwksVerifier {
+    async fn key(&self, provider: &Provider, kid: &str) -> Result<VerificationKey, TokenError> {
+        let jwk = provider.jwks.find(kid)
+            .await
+            .ok_or_else(|| TokenError::Invalid("No matching key found".into()))?;
+
+        VerificationKey::from_jwk(&jwk)
+    }
+}
+
+/// The signature algorithms a JWK may verify.
+///
+/// A key that names its `alg` is only good for that algorithm, otherwise
+/// every algorithm of its type and curve is allowed. Symmetric keys and keys
+/// meant for encryption are never used.
+fn key_algorithms(jwk: &Jwk) -> Result<Vec<Algorithm>, String> {
+    if matches!(jwk.common.public_key_use, Some(PublicKeyUse::Encryption)) {
+        return Err("Key is meant for encryption".into());
+    }
+
+    let supported = match &jwk.algorithm {
+        AlgorithmParameters::RSA(_) => RSA_ALGORITHMS.to_vec(),
+        AlgorithmParameters::EllipticCurve(params) => match params.curve {
+            EllipticCurve::P256 => vec![Algorithm::ES256],
+            EllipticCurve::P384 => vec![Algorithm::ES384],
+            ref curve => return Err(format!("Unsupported curve {:?}", curve)),
+        },
+        AlgorithmParameters::OctetKeyPair(params) => match params.curve {
+            EllipticCurve::Ed25519 => vec![Algorithm::EdDSA],
+            ref curve => return Err(format!("Unsupported curve {:?}", curve)),
+        },
+        AlgorithmParameters::OctetKey(_) => return Err("Symmetric keys are not accepted".into()),
+    };
+
+    let Some(alg) = jwk.common.key_algorithm else {
+        return Ok(supported);
+    };
+    match signature_algorithm(alg) {
+        Some(alg) if supported.contains(&alg) => Ok(vec![alg]),
+        _ => Err(format!("Key algorithm {:?} does not match its type", alg)),
+    }
+}
+
+fn signature_algorithm(alg: KeyAlgorithm) -> Option<Algorithm> {
+    match alg {
+        KeyAlgorithm::ES256 => Some(Algorithm::ES256),
+        KeyAlgorithm::ES384 => Some(Algorithm::ES384),
+        KeyAlgorithm::RS256 => Some(Algorithm::RS256),
+        KeyAlgorithm::RS384 => Some(Algorithm::RS384),
+        KeyAlgorithm::RS512 => Some(Algorithm::RS512),
+        KeyAlgorithm::PS256 => Some(Algorithm::PS256),
+        KeyAlgorithm::PS384 => Some(Algorithm::PS384),
+        KeyAlgorithm::PS512 => Some(Algorithm::PS512),
+        KeyAlgorithm::EdDSA => Some(Algorithm::EdDSA),
+        _ => None,
+    }
+}
+
+/// Check `exp`, `nbf` and `iat`, tolerating `leeway` seconds of skew between
+/// our clock and the provider's.
+fn check_times(claims: &Claims, now: u64, leeway: u64) -> Result<(), TokenError> {
+    if claims.exp + leeway <= now {
+        return Err(TokenError::Expired);
+    }
+    if claims.nbf.is_some_and(|nbf| nbf > now + leeway) {
+        return Err(TokenError::Invalid("Invalid token: not valid yet".into()));
+    }
+    if claims.iat > now + leeway {
+        return Err(TokenError::Invalid("Invalid token: issued in the future".into()));
+    }
+    Ok(())
+}
+
+/// Make sure the token was issued to our app client.
+fn check_audience(provider: &Provider, claims: &Claims) -> Result<(), TokenError> {
+    let client_id = provider.config.client_id.as_str();
+
+    let issued_to_us = if provider.is_cognito() {
+        match claims.token_use.as_deref() {
+            Some("access") => claims.client_id.as_deref() == Some(client_id),
+            Some("id") => claims.aud.as_ref().is_some_and(|aud| aud.contains(client_id)),
+            other => {
+                return Err(TokenError::Invalid(format!(
+                    "Invalid token: unsupported token_use {:?}",
+                    other.unwrap_or_default()
+                )));
+            }
+        }
+    } else {
+        // ID tokens name us in `aud`, JWT access tokens usually in `azp` or `client_id`
+        claims.aud.as_ref().is_some_and(|aud| aud.contains(client_id))
+            || claims.azp.as_deref() == Some(client_id)
+            || claims.client_id.as_deref() == Some(client_id)
+    };
+
+    if !issued_to_us {
+        return Err(TokenError::Invalid("Invalid token: issued to another client".into()));
+    }
+    Ok(())
+}
+
+fn token_error(e: jsonwebtoken::errors::Error) -> TokenError {
+    match e.kind() {
+        ErrorKind::ExpiredSignature => TokenError::Expired,
+        _ => TokenError::Invalid(format!("Invalid token: {}", e)),
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::{
+        config::{ProviderConfig, ProviderKind},
+        session::Audience,
+        clock::tests::now,
+    };
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde::Serialize;
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    pub const TEST_KID: &str = "test-key";
+
+    /// Trusts the locally generated test key under `TEST_KID` and nothing else.
+    pub struct TestVerifier;
+
+    #[async_trait]
+    impl TokenVerifier for TestVerifier {
+        async fn key(&self, _provider: &Provider, kid: &str) -> Result<VerificationKey, TokenError> {
+            if kid != TEST_KID {
+                return Err(TokenError::Invalid("No matching key found".into()));
+            }
+            Ok(VerificationKey {
+                key: DecodingKey::from_rsa_pem(include_bytes!("test_key.pub.pem")).unwrap(),
+                algorithms: vec![Algorithm::RS256],
+            })
+        }
+    }
+
+    /// A token signed with the test key.
+    pub fn sign(claims: &impl Serialize) -> String {
+        let header = Header {
+            kid: Some(TEST_KID.to_string()),
+            ..Header::new(Algorithm::RS256)
+        };
+        encode(&header, claims, &EncodingKey::from_rsa_pem(include_bytes!("test_key.pem")).unwrap()).unwrap()
+    }
+
+    async fn cognito_provider() -> Provider {
+        Provider::new(
+            ProviderConfig::for_tests("https://test.auth.amazoncognito.com"),
+            std::time::Duration::from_secs(3600),
+        )
+        .await
+        .unwrap()
+    }
+
+    fn access_claims(provider: &Provider) -> Claims {
+        Claims {
+            sub: "user-1".to_string(),
+            exp: now() + 3600,
+            iat: now(),
+            nbf: None,
+            iss: provider.metadata.issuer.clone(),
+            aud: None,
+            azp: None,
+            client_id: Some("test-client-id".to_string()),
+            token_use: Some("access".to_string()),
+            groups: Vec::new(),
+            email: None,
+            username: None,
+            extra: Default::default(),
+        }
+    }
+
+    fn error_message(result: Result<Claims, TokenError>) -> String {
+        match result {
+            Ok(_) => "verified".to_string(),
+            Err(TokenError::Expired) => "expired".to_string(),
+            Err(TokenError::Invalid(message)) => message,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify() {
+        let provider = cognito_provider().await;
+        let claims = access_claims(&provider);
+        let verified = TestVerifier.verify(&provider, &sign(&claims), now(), 0).await;
+        assert!(matches!(verified, Ok(verified) if verified == claims));
+    }
+
+    #[tokio::test]
+    async fn test_verify_rejects_bad_signatures() {
+        let provider = cognito_provider().await;
+        let token = sign(&access_claims(&provider));
+
+        // Claims swapped in after signing
+        let mut forged = access_claims(&provider);
+        forged.sub = "admin".to_string();
+        let forged_payload = sign(&forged).split('.').nth(1).unwrap().to_string();
+        let parts: Vec<_> = token.split('.').collect();
+        let tampered = format!("{}.{}.{}", parts[0], forged_payload, parts[2]);
+        assert!(error_message(TestVerifier.verify(&provider, &tampered, now(), 0).await).contains("InvalidSignature"));
+
+        // A key we do not know
+        let header = Header { kid: Some("other-key".to_string()), ..Header::new(Algorithm::RS256) };
+        let key = EncodingKey::from_rsa_pem(include_bytes!("test_key.pem")).unwrap();
+        let unknown_kid = encode(&header, &access_claims(&provider), &key).unwrap();
+        assert_eq!(error_message(TestVerifier.verify(&provider, &unknown_kid, now(), 0).await), "No matching key found");
+
+        // A symmetric algorithm, which must never be accepted
+        let header = Header { kid: Some(TEST_KID.to_string()), ..Header::new(Algorithm::HS256) };
+        let hmac = encode(&header, &access_claims(&provider), &EncodingKey::from_secret(b"secret")).unwrap();
+        assert!(error_message(TestVerifier.verify(&provider, &hmac, now(), 0).await).contains("Invalid token"));
+
+        // No key ID, or no token at all
+        let no_kid = encode(&Header::new(Algorithm::RS256), &access_claims(&provider), &key).unwrap();
+        assert_eq!(error_message(TestVerifier.verify(&provider, &no_kid, now(), 0).await), "No key ID in token");
+        assert!(error_message(TestVerifier.verify(&provider, "not-a-token", now(), 0).await).contains("Invalid token header"));
+    }
+
+    #[tokio::test]
+    async fn test_verify_rejects_wrong_claims() {
+        let provider = cognito_provider().await;
+
+        let mut wrong_client = access_claims(&provider);
+        wrong_client.client_id = Some("other-client".to_string());
+
+        // An ID token's `aud` is not looked at for access tokens
+        let mut aud_on_access_token = access_claims(&provider);
+        aud_on_access_token.client_id = None;
+        aud_on_access_token.aud = Some(Audience::One("test-client-id".to_string()));
+
+        let mut no_token_use = access_claims(&provider);
+        no_token_use.token_use = None;
+
+        let mut wrong_issuer = access_claims(&provider);
+        wrong_issuer.iss = "https://cognito-idp.us-east-1.amazonaws.com/us-east-1_other".to_string();
+
+        let mut expired = access_claims(&provider);
+        expired.exp = now() - 3600;
+
+        let mut id_token = access_claims(&provider);
+        id_token.token_use = Some("id".to_string());
+        id_token.client_id = None;
+        id_token.aud = Some(Audience::One("test-client-id".to_string()));
+
+        for (claims, expected) in [
+            (wrong_client, "issued to another client"),
+            (aud_on_access_token, "issued to another client"),
+            (no_token_use, "unsupported token_use"),
+            (wrong_issuer, "InvalidIssuer"),
+            (expired, "expired"),
+            (id_token, "verified"),
+        ] {
+            let message = error_message(TestVerifier.verify(&provider, &sign(&claims), now(), 0).await);
+            assert!(message.contains(expected), "{:?}: {}", claims, message);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify_leeway() {
+        let provider = cognito_provider().await;
+        let at = now();
+
+        let mut just_expired = access_claims(&provider);
+        just_expired.exp = at - 30;
+        let mut not_yet_valid = access_claims(&provider);
+        not_yet_valid.nbf = Some(at + 30);
+        let mut issued_later = access_claims(&provider);
+        issued_later.iat = at + 30;
+
+        for (claims, strict) in [
+            (just_expired, "expired"),
+            (not_yet_valid, "not valid yet"),
+            (issued_later, "issued in the future"),
+        ] {
+            let token = sign(&claims);
+            assert_eq!(error_message(TestVerifier.verify(&provider, &token, at, 60).await), "verified");
+            assert!(error_message(TestVerifier.verify(&provider, &token, at, 0).await).contains(strict));
+        }
+    }
+
+    fn jwk(value: serde_json::Value) -> Jwk {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_key_algorithms() {
+        let rsa = serde_json::json!({ "kty": "RSA", "n": "AQAB", "e": "AQAB" });
+        assert_eq!(key_algorithms(&jwk(rsa.clone())).unwrap(), RSA_ALGORITHMS.to_vec());
+
+        let mut ps256 = rsa.clone();
+        ps256["alg"] = "PS256".into();
+        assert_eq!(key_algorithms(&jwk(ps256)).unwrap(), vec![Algorithm::PS256]);
+
+        let ec = serde_json::json!({ "kty": "EC", "crv": "P-384", "x": "AQAB", "y": "AQAB" });
+        assert_eq!(key_algorithms(&jwk(ec.clone())).unwrap(), vec![Algorithm::ES384]);
+
+        // An `alg` of the wrong family, encryption keys and shared secrets
+        let mut confused = ec.clone();
+        confused["alg"] = "RS256".into();
+        let mut encryption = rsa.clone();
+        encryption["use"] = "enc".into();
+        let mut encryption_alg = rsa.clone();
+        encryption_alg["alg"] = "RSA-OAEP".into();
+        let secret = serde_json::json!({ "kty": "oct", "k": "c2VjcmV0" });
+        for key in [confused, encryption, encryption_alg, secret] {
+            assert!(key_algorithms(&jwk(key.clone())).is_err(), "{}", key);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_jwks_verifier_algorithms() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/.well-known/openid-configuration"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "issuer": mock_server.uri(),
+                "authorization_endpoint": format!("{}/authorize", mock_server.uri()),
+                "token_endpoint": format!("{}/token", mock_server.uri()),
+                "jwks_uri": format!("{}/jwks", mock_server.uri())
+            })))
+            .mount(&mock_server)
+            .await;
+
+        // Keys published only as certificates, one of them pinned to PS256
+        let certs: serde_json::Value = serde_json::from_str(include_str!("test_certs.json")).unwrap();
+        Mock::given(method("GET"))
+            .and(path("/jwks"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({ "keys": [
+                { "kid": "ec", "x5c": [certs["ec"]] },
+                { "kid": "ed", "x5c": [certs["ed"]] },
+                { "kid": "rsa", "alg": "PS256", "x5c": [certs["leaf"], certs["ca"]] },
+            ]})))
+            .mount(&mock_server)
+            .await;
+
+        let provider = Provider::new(
+            ProviderConfig {
+                kind: ProviderKind::Oidc { issuer: mock_server.uri() },
+                ..ProviderConfig::for_tests("https://test.auth.amazoncognito.com")
+            },
+            std::time::Duration::from_secs(3600),
+        )
+        .await
+        .unwrap();
+        provider.jwks.refresh().await.unwrap();
+
+        let rsa = EncodingKey::from_rsa_pem(include_bytes!("test_key.pem")).unwrap();
+        let ec = EncodingKey::from_ec_pem(include_bytes!("test_ec_key.pem")).unwrap();
+        let ed = EncodingKey::from_ed_pem(include_bytes!("test_ed_key.pem")).unwrap();
+        let secret = EncodingKey::from_secret(b"secret");
+
+        for (kid, alg, key, expected) in [
+            ("ec", Algorithm::ES256, &ec, "verified"),
+            ("ed", Algorithm::EdDSA, &ed, "verified"),
+            ("rsa", Algorithm::PS256, &rsa, "verified"),
+            // The key allows PS256 only, and never another key type's algorithm
+            ("rsa", Algorithm::RS256, &rsa, "does not match the key"),
+            ("ec", Algorithm::RS256, &rsa, "does not match the key"),
+            ("rsa", Algorithm::HS256, &secret, "does not match the key"),
+        ] {
+            let header = Header { kid: Some(kid.to_string()), ..Header::new(alg) };
+            let token = encode(&header, &access_claims(&provider), key).unwrap();
+            let message = error_message(JwksVerifier.verify(&provider, &token, now(), 0).await);
+            assert!(message.contains(expected), "{} {:?}: {}", kid, alg, message);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_audience_oidc() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/.well-known/openid-configuration"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "issuer": mock_server.uri(),
+                "authorization_endpoint": format!("{}/authorize", mock_server.uri()),
+                "token_endpoint": format!("{}/token", mock_server.uri()),
+                "jwks_uri": format!("{}/jwks", mock_server.uri())
+            })))
+            .mount(&mock_server)
+            .await;
+
+        let provider = Provider::new(
+            ProviderConfig {
+                name: "corp".to_string(),
+                label: "Corp".to_string(),
+                kind: ProviderKind::Oidc { issuer: mock_server.uri() },
+                client_id: "oidc-client".to_string(),
+                client_secret: String::new(),
+                scopes: "openid".to_string(),
+            },
+            std::time::Duration::from_secs(3600),
+        )
+        .await
+        .unwrap();
+
+        // No token_use needed, the audience may be a list
+        let mut claims = access_claims(&provider);
+        claims.client_id = None;
+        claims.token_use = None;
+        claims.aud = Some(Audience::Many(vec!["other".to_string(), "oidc-client".to_string()]));
+        assert!(check_audience(&provider, &claims).is_ok());
+
+        claims.aud = Some(Audience::One("other".to_string()));
+        assert!(check_audience(&provider, &claims).is_err());
+        claims.azp = Some("oidc-client".to_string());
+        assert!(check_audience(&provider, &claims).is_ok());
+    }
+}